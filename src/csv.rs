@@ -0,0 +1,186 @@
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+};
+
+use anyhow::{anyhow, Result};
+use geojson::feature::Id;
+use itertools::Itertools;
+use osmpbfreader::OsmObj;
+
+use crate::{geom, util::Objects, wkt};
+
+/// Columns written if no others are selected.
+pub const DEFAULT_FIELDS: [Field; 5] = [
+    Field::Id,
+    Field::Name,
+    Field::AdminLevel,
+    Field::Ars,
+    Field::Geometry,
+];
+
+/// A column of the CSV output: a property of the feature, its geometry or a tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    Id,
+    OsmType,
+    Name,
+    DisplayName,
+    AdminLevel,
+    Ars,
+    AreaKm2,
+    Center,
+    /// The geometry as WKT.
+    Geometry,
+    /// The value of a tag, which needs to be copied into the properties.
+    Tag(String),
+}
+
+/// Names of the fields other than tags, as accepted by `Field::from_str`.
+const FIELD_NAMES: [(&str, Field); 9] = [
+    ("id", Field::Id),
+    ("osm_type", Field::OsmType),
+    ("name", Field::Name),
+    ("display_name", Field::DisplayName),
+    ("admin_level", Field::AdminLevel),
+    ("ars", Field::Ars),
+    ("area_km2", Field::AreaKm2),
+    ("center", Field::Center),
+    ("geometry_wkt", Field::Geometry),
+];
+
+impl std::str::FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(key) = s.strip_prefix("tag:") {
+            return Ok(Self::Tag(key.to_string()));
+        }
+
+        FIELD_NAMES
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, field)| field.clone())
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown field '{s}', expected one of {} or 'tag:<key>'",
+                    FIELD_NAMES.iter().map(|(name, _)| name).join(", ")
+                )
+            })
+    }
+}
+
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tag(key) => write!(f, "tag:{key}"),
+            field => {
+                let (name, _) = FIELD_NAMES
+                    .iter()
+                    .find(|(_, other)| other == field)
+                    .expect("all fields but tags are named");
+                f.write_str(name)
+            }
+        }
+    }
+}
+
+/// Write a CSV row with the `fields` of the feature for each object in `objs` matching `pred`,
+/// after a header naming them if `header` is set. Tags need to be in `options.properties` to be
+/// found.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails or, with `options.strict`, if an object cannot be converted.
+pub fn write(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    fields: &[Field],
+    header: bool,
+    out: impl io::Write,
+    options: &geom::Options,
+) -> Result<geom::Summary> {
+    // Use a buffered writer to amortize flushes.
+    let mut buffer = options.buffer(out);
+
+    if header {
+        writeln!(buffer, "{}", fields.iter().join(","))?;
+    }
+
+    let mut summary = geom::Summary::default();
+    for feature in geom::features(objs, pred, options, &mut summary.skipped) {
+        let feature = feature?;
+        let property = |key: &str| match feature.property(key) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+            None => String::new(),
+        };
+
+        let id = match &feature.id {
+            Some(Id::Number(n)) => n.to_string(),
+            Some(Id::String(s)) => s.clone(),
+            None => String::new(),
+        };
+
+        let row = fields.iter().map(|field| match field {
+            Field::Id => id.clone(),
+            Field::OsmType => property("osm_type"),
+            Field::Name => property("name"),
+            Field::DisplayName => property("display_name"),
+            Field::AdminLevel => property("adminLevel"),
+            Field::Ars => property("ars"),
+            Field::AreaKm2 => property("area_km2"),
+            Field::Center => property("center"),
+            Field::Geometry => feature
+                .geometry
+                .as_ref()
+                .map(|g| wkt::to_wkt(&g.value))
+                .unwrap_or_default(),
+            Field::Tag(key) => property(key),
+        });
+
+        writeln!(
+            buffer,
+            "{}",
+            row.map(|field| quote(&field).into_owned()).join(",")
+        )?;
+        summary.written += 1;
+    }
+
+    Ok(summary)
+}
+
+/// Quote a field according to RFC 4180 if it contains separators, quotes or line breaks.
+fn quote(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{quote, Field};
+
+    #[test]
+    fn quote_fields() {
+        assert_eq!(quote("Berlin"), "Berlin");
+        assert_eq!(quote("Halle (Saale), Stadt"), "\"Halle (Saale), Stadt\"");
+        assert_eq!(quote("Die \"Stadt\""), "\"Die \"\"Stadt\"\"\"");
+        assert_eq!(quote("a\nb"), "\"a\nb\"");
+    }
+
+    #[test]
+    fn parse_fields() {
+        assert_eq!("area_km2".parse::<Field>().unwrap(), Field::AreaKm2);
+        assert_eq!(
+            "tag:population".parse::<Field>().unwrap(),
+            Field::Tag("population".to_string())
+        );
+        assert_eq!(Field::Geometry.to_string(), "geometry_wkt");
+
+        let error = "area".parse::<Field>().unwrap_err().to_string();
+        assert!(error.starts_with("unknown field 'area', expected one of id, osm_type,"));
+    }
+}
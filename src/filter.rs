@@ -1,41 +1,741 @@
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Context};
+use itertools::Itertools;
 use osmpbfreader::OsmObj;
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
 
 /// Filter for relations having name and a range of `admin_level`.
+#[must_use]
 pub fn all(obj: &OsmObj) -> bool {
     obj.is_relation()
 }
 
-/// Filter for relations. In addition to `filter::all`, add boundary types.
-pub fn by_target(obj: &OsmObj) -> bool {
-    let tags = obj.tags();
-    all(obj)
-        && tags.contains_key("name")
-        && tags
-            .get("type")
-            .map_or(false, |value| matches!(value.as_str(), "boundary"))
-        && tags
-            .get("boundary")
-            .map_or(false, |value| matches!(value.as_str(), "administrative"))
-        && tags.contains_key("de:regionalschluessel")
-        && tags.get("admin_level").map_or(false, |admin_level| {
-            matches!(admin_level.as_str(), "2" | "4" | "6" | "7" | "8")
+/// Administrative levels selected by `filter::by_target` unless configured otherwise.
+pub const DEFAULT_ADMIN_LEVELS: [u8; 5] = [2, 4, 6, 7, 8];
+
+/// Tag identifying relations that `filter::by_target` requires unless configured otherwise.
+pub const DEFAULT_ID_TAG: &str = "de:regionalschluessel";
+
+/// Filter for relations. In addition to `filter::all`, add boundary types, restrict
+/// `admin_level` to `admin_levels` and require the tag `id_tag` if given.
+pub fn by_target(admin_levels: &[u8], id_tag: Option<&str>) -> impl Fn(&OsmObj) -> bool {
+    let reject = rejection(admin_levels, id_tag);
+
+    move |obj: &OsmObj| all(obj) && reject(obj).is_none()
+}
+
+/// Kind of OSM object to select with `filter::by_types`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectType {
+    Node,
+    Way,
+    Relation,
+}
+
+impl ObjectType {
+    /// The kind of `obj`.
+    #[must_use]
+    pub fn of(obj: &OsmObj) -> Self {
+        match obj {
+            OsmObj::Node(_) => Self::Node,
+            OsmObj::Way(_) => Self::Way,
+            OsmObj::Relation(_) => Self::Relation,
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Node => "node",
+            Self::Way => "way",
+            Self::Relation => "relation",
+        })
+    }
+}
+
+impl std::str::FromStr for ObjectType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "node" => Self::Node,
+            "way" => Self::Way,
+            "relation" => Self::Relation,
+            _ => bail!("expected 'relation', 'way' or 'node'"),
         })
+    }
 }
 
-/// Filter relations by a query that can be a substring or a regex pattern
-pub fn by_query(query: &str) -> impl Fn(&OsmObj) -> bool {
-    let pattern = query.to_lowercase();
-    let regex = Regex::new(query).ok();
+/// Filter for objects of `types`. Relations are selected like in `filter::by_target`, ways and
+/// nodes if they have a name.
+pub fn by_types(
+    types: &[ObjectType],
+    admin_levels: &[u8],
+    id_tag: Option<&str>,
+) -> impl Fn(&OsmObj) -> bool {
+    let types = types.to_vec();
+    let by_target = by_target(admin_levels, id_tag);
 
     move |obj: &OsmObj| {
-        if let Some(name) = obj.tags().get("name") {
-            match &regex {
-                Some(re) => re.is_match(name), // Use regex for matching if it's valid
-                None => name.to_lowercase().contains(&pattern), // Fallback to case-insensitive substring match
+        types.contains(&ObjectType::of(obj))
+            && match obj {
+                OsmObj::Relation(_) => by_target(obj),
+                OsmObj::Way(_) | OsmObj::Node(_) => obj.tags().contains_key("name"),
             }
+    }
+}
+
+/// Reason for `filter::by_target` to drop a relation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    MissingName,
+    /// The `type` tag, if any, is not `boundary`.
+    Type(Option<String>),
+    /// The `boundary` tag, if any, is not `administrative`.
+    Boundary(Option<String>),
+    /// The configured id tag is missing.
+    MissingIdTag(String),
+    /// The `admin_level` tag, if any, is not one of the selected levels.
+    AdminLevel(Option<String>),
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = |value: &Option<String>| match value {
+            Some(value) => format!("'{value}'"),
+            None => "missing".to_string(),
+        };
+
+        match self {
+            Self::MissingName => write!(f, "'name' is missing"),
+            Self::Type(v) => write!(f, "'type' is {} instead of 'boundary'", value(v)),
+            Self::Boundary(v) => {
+                write!(f, "'boundary' is {} instead of 'administrative'", value(v))
+            }
+            Self::MissingIdTag(key) => write!(f, "'{key}' is missing"),
+            Self::AdminLevel(v) => write!(f, "'admin_level' is {} and not selected", value(v)),
+        }
+    }
+}
+
+/// Check relations like `filter::by_target`, but return the first reason to drop a relation, if
+/// any.
+pub fn rejection(
+    admin_levels: &[u8],
+    id_tag: Option<&str>,
+) -> impl Fn(&OsmObj) -> Option<Rejection> {
+    let admin_levels = admin_levels.iter().copied().collect::<BTreeSet<_>>();
+    let id_tag = id_tag.map(str::to_string);
+
+    move |obj: &OsmObj| {
+        let tags = obj.tags();
+        let value = |key: &str| tags.get(key).map(ToString::to_string);
+
+        if !tags.contains_key("name") {
+            Some(Rejection::MissingName)
+        } else if !tags.contains("type", "boundary") {
+            Some(Rejection::Type(value("type")))
+        } else if !tags.contains("boundary", "administrative") {
+            Some(Rejection::Boundary(value("boundary")))
+        } else if let Some(key) = id_tag
+            .as_ref()
+            .filter(|key| !tags.contains_key(key.as_str()))
+        {
+            Some(Rejection::MissingIdTag(key.clone()))
+        } else if !tags
+            .get("admin_level")
+            .and_then(|admin_level| parse_admin_level(admin_level))
+            .is_some_and(|level| admin_levels.contains(&level))
+        {
+            Some(Rejection::AdminLevel(value("admin_level")))
         } else {
-            false // If the object doesn't have a name tag, it doesn't match
+            None
+        }
+    }
+}
+
+/// Parse an `admin_level` value. Malformed values like "8;9" or "4a" found in real data yield
+/// their first number.
+#[must_use]
+pub fn parse_admin_level(value: &str) -> Option<u8> {
+    value
+        .split(|c: char| !c.is_ascii_digit())
+        .find_map(|number| number.parse().ok())
+}
+
+/// Contiguous admin levels like "2..=8", as alternative to listing them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdminLevelRange(std::ops::RangeInclusive<u8>);
+
+impl AdminLevelRange {
+    /// The admin levels in the range, to pass to `filter::by_target`.
+    #[must_use]
+    pub fn levels(&self) -> Vec<u8> {
+        self.0.clone().collect()
+    }
+}
+
+impl std::str::FromStr for AdminLevelRange {
+    type Err = anyhow::Error;
+
+    /// Parse an inclusive range "<start>..=<end>" or an exclusive one "<start>..<end>".
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some((start, end)) = s.split_once("..") else {
+            bail!("expected a range like '2..=8'");
+        };
+        let level = |value: &str| -> anyhow::Result<u8> {
+            let level = value
+                .parse::<u8>()
+                .with_context(|| format!("'{value}' is no admin level"))?;
+            if !(1..=11).contains(&level) {
+                bail!("admin level {level} is out of the range 1 to 11");
+            }
+            Ok(level)
+        };
+
+        let start = level(start)?;
+        let end = match end.strip_prefix('=') {
+            Some(end) => level(end)?,
+            None => level(end)?
+                .checked_sub(1)
+                .with_context(|| format!("'{s}' is empty"))?,
+        };
+        if start > end {
+            bail!("'{s}' is empty, the start must not exceed the end");
+        }
+
+        Ok(Self(start..=end))
+    }
+}
+
+/// How `filter::by_query` interprets a query.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QueryMode {
+    /// Use the query as regex pattern if it is one, else as substring.
+    #[default]
+    Auto,
+    /// Require a valid regex pattern.
+    Regex,
+    /// Match the query as substring, even if it contains special characters.
+    Literal,
+}
+
+impl std::str::FromStr for QueryMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "regex" => Self::Regex,
+            "literal" => Self::Literal,
+            _ => bail!("expected 'auto', 'regex' or 'literal'"),
+        })
+    }
+}
+
+/// Filter relations by a query that can be a substring or a regex pattern, as selected by
+/// `mode`. The query is matched against the values of the tags `keys`; a match in any of them
+/// counts. Unless `case_sensitive` is set, both substring and pattern match ignore case.
+///
+/// # Errors
+///
+/// Fails if `mode` is `QueryMode::Regex` and the query is no valid pattern.
+pub fn by_query(
+    query: &str,
+    keys: &[String],
+    case_sensitive: bool,
+    mode: QueryMode,
+) -> anyhow::Result<impl Fn(&OsmObj) -> bool> {
+    by_queries(&[query.to_string()], keys, case_sensitive, mode)
+}
+
+/// Filter relations matching any of `queries` as in `by_query`. All queries are combined into a
+/// single pattern, with substrings escaped, so matching is about as fast as for one query.
+///
+/// # Errors
+///
+/// Fails if `mode` is `QueryMode::Regex` and a query is no valid pattern.
+pub fn by_queries(
+    queries: &[String],
+    keys: &[String],
+    case_sensitive: bool,
+    mode: QueryMode,
+) -> anyhow::Result<impl Fn(&OsmObj) -> bool> {
+    let is_pattern = |query: &str| RegexBuilder::new(query).build().map(|_| ());
+    let alternatives = queries
+        .iter()
+        .map(|query| {
+            Ok(match mode {
+                QueryMode::Auto if is_pattern(query).is_ok() => query.clone(),
+                QueryMode::Regex => {
+                    is_pattern(query).with_context(|| format!("invalid pattern '{query}'"))?;
+                    query.clone()
+                }
+                QueryMode::Auto | QueryMode::Literal => regex::escape(query),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let regex = RegexBuilder::new(&alternatives.iter().map(|a| format!("(?:{a})")).join("|"))
+        .case_insensitive(!case_sensitive)
+        .build()
+        .context("queries are too complex")?;
+    let keys = keys.to_vec();
+
+    Ok(move |obj: &OsmObj| {
+        let tags = obj.tags();
+        // Objects without any of the tags don't match
+        keys.iter()
+            .filter_map(|key| tags.get(key.as_str()))
+            .any(|value| regex.is_match(value))
+    })
+}
+
+/// Filter relations *not* matching `pattern` as in `by_query`. Relations without any of the tags
+/// `keys` can't match and are kept.
+///
+/// # Errors
+///
+/// Fails if `mode` is `QueryMode::Regex` and `pattern` is no valid pattern.
+pub fn excluding(
+    pattern: &str,
+    keys: &[String],
+    case_sensitive: bool,
+    mode: QueryMode,
+) -> anyhow::Result<impl Fn(&OsmObj) -> bool> {
+    let matches = by_query(pattern, keys, case_sensitive, mode)?;
+
+    Ok(move |obj: &OsmObj| !matches(obj))
+}
+
+/// Filter objects whose regional key `de:regionalschluessel` starts with `prefix`, i.e. the areas
+/// within the region with that key. Objects without the tag are dropped.
+pub fn by_ars_prefix(prefix: String) -> impl Fn(&OsmObj) -> bool {
+    move |obj: &OsmObj| {
+        obj.tags()
+            .get(DEFAULT_ID_TAG)
+            .is_some_and(|ars| ars.starts_with(&prefix))
+    }
+}
+
+/// Expression over the tags of an object, like
+/// `boundary=administrative and admin_level>=6 and name~Berlin`. Tags are compared with `=`,
+/// `!=`, `~` for a regex pattern, and `<`, `<=`, `>` or `>=` for numbers. Comparisons are joined
+/// with `and`, which binds stronger than `or`, and grouped with parentheses. Values with spaces
+/// or operators need single or double quotes.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare { key: String, comparison: Comparison },
+}
+
+/// Comparison of a tag value in an `Expr`.
+#[derive(Debug, Clone)]
+pub enum Comparison {
+    Equal(String),
+    NotEqual(String),
+    Matches(Regex),
+    Less(f64),
+    AtMost(f64),
+    Greater(f64),
+    AtLeast(f64),
+}
+
+impl Expr {
+    /// Whether the tags of `obj` satisfy the expression. Comparisons with a tag `obj` lacks, or
+    /// numeric comparisons with a value which is no number, are false, except for `!=`.
+    #[must_use]
+    pub fn matches(&self, obj: &OsmObj) -> bool {
+        let (key, comparison) = match self {
+            Self::And(a, b) => return a.matches(obj) && b.matches(obj),
+            Self::Or(a, b) => return a.matches(obj) || b.matches(obj),
+            Self::Compare { key, comparison } => (key, comparison),
+        };
+        let value = obj
+            .tags()
+            .get(key.as_str())
+            .map(smartstring::alias::String::as_str);
+        let number = || value.and_then(|value| value.trim().parse::<f64>().ok());
+
+        match comparison {
+            Comparison::Equal(expected) => value == Some(expected.as_str()),
+            Comparison::NotEqual(expected) => value != Some(expected.as_str()),
+            Comparison::Matches(regex) => value.is_some_and(|value| regex.is_match(value)),
+            Comparison::Less(n) => number().is_some_and(|value| value < *n),
+            Comparison::AtMost(n) => number().is_some_and(|value| value <= *n),
+            Comparison::Greater(n) => number().is_some_and(|value| value > *n),
+            Comparison::AtLeast(n) => number().is_some_and(|value| value >= *n),
+        }
+    }
+}
+
+impl std::str::FromStr for Expr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut tokens = tokenize(s)?.into_iter().peekable();
+        let expr = parse_or(&mut tokens)?;
+        if let Some(token) = tokens.next() {
+            bail!("unexpected {token} after a complete expression");
+        }
+        Ok(expr)
+    }
+}
+
+/// Token of an `Expr`.
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Operator(String),
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Word(word) | Self::Operator(word) => write!(f, "'{word}'"),
+            Self::Quoted(word) => write!(f, "'\"{word}\"'"),
+            Self::Open => write!(f, "'('"),
+            Self::Close => write!(f, "')'"),
+        }
+    }
+}
+
+type Tokens = std::iter::Peekable<std::vec::IntoIter<Token>>;
+
+/// Split the expression `s` into words, quoted values, operators and parentheses.
+fn tokenize(s: &str) -> anyhow::Result<Vec<Token>> {
+    const SPECIAL: [char; 9] = ['(', ')', '=', '!', '~', '<', '>', '"', '\''];
+
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            _ if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '=' | '!' | '~' | '<' | '>' => {
+                chars.next();
+                let mut operator = c.to_string();
+                if matches!(c, '!' | '<' | '>') && chars.next_if_eq(&'=').is_some() {
+                    operator.push('=');
+                }
+                if operator == "!" {
+                    bail!("expected '!=' instead of '!'");
+                }
+                tokens.push(Token::Operator(operator));
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(q) => value.push(q),
+                        None => bail!("missing closing {c} after '{value}'"),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && !SPECIAL.contains(&c))
+                {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Parse comparisons joined by `and`, which are joined by `or`.
+fn parse_or(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let mut expr = parse_and(tokens)?;
+    while tokens
+        .next_if(|token| matches!(token, Token::Word(w) if w.eq_ignore_ascii_case("or")))
+        .is_some()
+    {
+        expr = Expr::Or(Box::new(expr), Box::new(parse_and(tokens)?));
+    }
+    Ok(expr)
+}
+
+/// Parse comparisons joined by `and`.
+fn parse_and(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let mut expr = parse_comparison(tokens)?;
+    while tokens
+        .next_if(|token| matches!(token, Token::Word(w) if w.eq_ignore_ascii_case("and")))
+        .is_some()
+    {
+        expr = Expr::And(Box::new(expr), Box::new(parse_comparison(tokens)?));
+    }
+    Ok(expr)
+}
+
+/// Parse a comparison like `admin_level>=6`, or an expression in parentheses.
+fn parse_comparison(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let key = match tokens.next() {
+        Some(Token::Open) => {
+            let expr = parse_or(tokens)?;
+            if tokens.next() != Some(Token::Close) {
+                bail!("missing ')'");
+            }
+            return Ok(expr);
+        }
+        Some(Token::Word(key) | Token::Quoted(key)) => key,
+        Some(token) => bail!("expected a comparison like 'key=value' instead of {token}"),
+        None => bail!("expected a comparison like 'key=value' at the end"),
+    };
+    let Some(Token::Operator(operator)) = tokens.next() else {
+        bail!("expected an operator like '=' after '{key}'");
+    };
+    let Some(Token::Word(value) | Token::Quoted(value)) = tokens.next() else {
+        bail!("expected a value after '{key}{operator}'");
+    };
+
+    let number = || {
+        value
+            .parse::<f64>()
+            .with_context(|| format!("'{value}' is no number to compare '{key}' with"))
+    };
+    let comparison = match operator.as_str() {
+        "=" => Comparison::Equal(value),
+        "!=" => Comparison::NotEqual(value),
+        "~" => Comparison::Matches(
+            Regex::new(&value).with_context(|| format!("invalid pattern '{value}'"))?,
+        ),
+        "<" => Comparison::Less(number()?),
+        "<=" => Comparison::AtMost(number()?),
+        ">" => Comparison::Greater(number()?),
+        _ => Comparison::AtLeast(number()?),
+    };
+
+    Ok(Expr::Compare { key, comparison })
+}
+
+#[cfg(test)]
+mod test {
+    use osmpbfreader::{Node, NodeId, OsmObj, Tags};
+
+    use super::{ObjectType, QueryMode, Rejection};
+    use crate::geom::test::{add_relation, add_tags};
+    use crate::util::Objects;
+
+    /// A relation with `tags` and no members.
+    fn relation(tags: &[(&str, &str)]) -> OsmObj {
+        let mut relation = add_relation(&mut Objects::default(), 1, &[]);
+        add_tags(&mut relation, tags);
+        relation
+    }
+
+    #[test]
+    fn rejection() {
+        let reject = super::rejection(&[8], Some("ref"));
+
+        let mut tags = vec![];
+        assert_eq!(reject(&relation(&tags)), Some(Rejection::MissingName));
+
+        tags.push(("name", "Musterstadt"));
+        assert_eq!(reject(&relation(&tags)), Some(Rejection::Type(None)));
+
+        tags.push(("type", "boundary"));
+        tags.push(("boundary", "political"));
+        assert_eq!(
+            reject(&relation(&tags)),
+            Some(Rejection::Boundary(Some("political".to_string())))
+        );
+
+        tags[2] = ("boundary", "administrative");
+        assert_eq!(
+            reject(&relation(&tags)),
+            Some(Rejection::MissingIdTag("ref".to_string()))
+        );
+
+        tags.push(("ref", "1"));
+        tags.push(("admin_level", "6"));
+        assert_eq!(
+            reject(&relation(&tags)),
+            Some(Rejection::AdminLevel(Some("6".to_string())))
+        );
+
+        tags[4] = ("admin_level", "8");
+        assert_eq!(reject(&relation(&tags)), None);
+        assert!(super::by_target(&[8], Some("ref"))(&relation(&tags)));
+    }
+
+    #[test]
+    fn by_types() {
+        let node = |name: Option<&str>| {
+            let mut tags = Tags::new();
+            if let Some(name) = name {
+                tags.insert("name".into(), name.into());
+            }
+            OsmObj::Node(Node {
+                id: NodeId(1),
+                tags,
+                decimicro_lat: 0,
+                decimicro_lon: 0,
+            })
+        };
+        let relation = relation(&[]);
+
+        let relations = super::by_types(&[ObjectType::Relation], &[8], None);
+        assert!(!relations(&node(Some("Brunnen"))));
+        assert!(!relations(&relation));
+
+        let nodes = super::by_types(&[ObjectType::Node, ObjectType::Way], &[8], None);
+        assert!(nodes(&node(Some("Brunnen"))));
+        assert!(!nodes(&node(None)));
+        assert!(!nodes(&relation));
+
+        assert_eq!("way".parse::<ObjectType>().ok(), Some(ObjectType::Way));
+        assert!("area".parse::<ObjectType>().is_err());
+    }
+
+    #[test]
+    fn parse_admin_level() {
+        use super::parse_admin_level;
+
+        assert_eq!(parse_admin_level("8"), Some(8));
+        assert_eq!(parse_admin_level("8;9"), Some(8));
+        assert_eq!(parse_admin_level("4a"), Some(4));
+        assert_eq!(parse_admin_level(" 10"), Some(10));
+        assert_eq!(parse_admin_level("yes"), None);
+        assert_eq!(parse_admin_level("300"), None);
+    }
+
+    #[test]
+    fn by_query_mode() {
+        let relation = relation(&[("name", "St. Louis (city)")]);
+        let keys = ["name".to_string()];
+        let matches =
+            |query: &str, mode| super::by_query(query, &keys, false, mode).unwrap()(&relation);
+
+        assert!(matches("st. louis (city)", QueryMode::Literal));
+        assert!(!matches("St.Louis", QueryMode::Literal));
+        assert!(matches("^St. Louis", QueryMode::Regex));
+        assert!(!matches("^St. Louis", QueryMode::Literal));
+        // Not a valid pattern, so auto mode falls back to the substring.
+        assert!(matches("louis (", QueryMode::Auto));
+        assert!(super::by_query("louis (", &keys, false, QueryMode::Regex).is_err());
+    }
+
+    #[test]
+    fn by_queries() {
+        let named = |name| relation(&[("name", name)]);
+        let keys = ["name".to_string()];
+        let queries = ["^Berlin$", "Louis (", "Köln"].map(String::from);
+
+        let matches = super::by_queries(&queries, &keys, false, QueryMode::Auto).unwrap();
+        assert!(matches(&named("berlin")));
+        assert!(!matches(&named("Berlingen")));
+        assert!(matches(&named("St. Louis (city)")));
+        assert!(matches(&named("KÖLN")));
+        assert!(!matches(&named("Bonn")));
+
+        let matches = super::by_queries(&queries, &keys, true, QueryMode::Literal).unwrap();
+        assert!(!matches(&named("Berlin")));
+        assert!(matches(&named("St. Louis (city)")));
+        assert!(!matches(&named("KÖLN")));
+
+        assert!(super::by_queries(&queries, &keys, false, QueryMode::Regex).is_err());
+    }
+
+    #[test]
+    fn by_ars_prefix() {
+        let with_ars = |ars| relation(&[("de:regionalschluessel", ars)]);
+        let filter = super::by_ars_prefix("05".to_string());
+
+        assert!(filter(&with_ars("051110000000")));
+        assert!(filter(&with_ars("05")));
+        assert!(!filter(&with_ars("061110000000")));
+        assert!(!filter(&relation(&[])));
+    }
+
+    #[test]
+    fn parse_admin_level_range() {
+        use super::AdminLevelRange;
+
+        let levels = |s: &str| s.parse::<AdminLevelRange>().map(|range| range.levels());
+
+        assert_eq!(levels("2..=8").unwrap(), [2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(levels("4..6").unwrap(), [4, 5]);
+        assert_eq!(levels("8..=8").unwrap(), [8]);
+        assert!(levels("8..=2").is_err());
+        assert!(levels("8..8").is_err());
+        assert!(levels("0..=4").is_err());
+        assert!(levels("2..=12").is_err());
+        assert!(levels("2-8").is_err());
+        assert!(levels("a..=8").is_err());
+    }
+
+    #[test]
+    fn parse_expr() {
+        use super::Expr;
+
+        let berlin = relation(&[
+            ("boundary", "administrative"),
+            ("admin_level", "4"),
+            ("name", "Berlin"),
+        ]);
+        let mitte = relation(&[
+            ("boundary", "administrative"),
+            ("admin_level", "9"),
+            ("name", "Berlin-Mitte"),
+            ("name:prefix", "Ortsteil"),
+        ]);
+        let matches = |expr: &str| {
+            let expr = expr.parse::<Expr>().unwrap();
+            [expr.matches(&berlin), expr.matches(&mitte)]
+        };
+
+        assert_eq!(
+            matches("boundary=administrative and admin_level>=6 and name~Berlin"),
+            [false, true]
+        );
+        assert_eq!(
+            matches("admin_level<5 or name:prefix='Ortsteil'"),
+            [true, true]
+        );
+        assert_eq!(
+            matches("name~^Berlin$ or admin_level>4 and name~x"),
+            [true, false]
+        );
+        assert_eq!(
+            matches("(name~^Berlin$ or admin_level>4) and name~x"),
+            [false, false]
+        );
+        assert_eq!(matches("name:prefix!=Ortsteil"), [true, false]);
+        assert_eq!(matches("population>0"), [false, false]);
+        assert_eq!(
+            matches(r#"name="Berlin-Mitte" or name="it's""#),
+            [false, true]
+        );
+
+        for invalid in [
+            "",
+            "name",
+            "name=",
+            "name!Berlin",
+            "admin_level>=six",
+            "name~(",
+            "(name=Berlin",
+            "name=Berlin)",
+            "name='Berlin",
+            "name=Berlin and",
+        ] {
+            assert!(invalid.parse::<Expr>().is_err(), "{invalid}");
         }
     }
 }
@@ -1,20 +1,26 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     hash::Hash,
     io::Write,
     io::{self, BufWriter},
+    path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use geojson::{self, GeoJson, Geometry};
-use log::error;
-use osmpbfreader::{OsmId, OsmObj, Ref, Way};
+use itertools::Itertools;
+use log::{debug, error, warn};
+use osmpbfreader::{Node, NodeId, OsmId, OsmObj, Ref, Relation, RelationId, Tags, Way, WayId};
+use rayon::prelude::*;
 use serde_json::json;
 
-use crate::filter;
+use crate::{
+    filter,
+    util::{self, Objects},
+};
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Default)]
-struct Position(
+pub(crate) struct Position(
     ordered_float::OrderedFloat<f64>,
     ordered_float::OrderedFloat<f64>,
 );
@@ -23,6 +29,31 @@ impl Position {
     pub fn new(x: f64, y: f64) -> Self {
         Self(x.into(), y.into())
     }
+
+    fn distance(&self, other: &Position) -> f64 {
+        (*self.0 - *other.0).hypot(*self.1 - *other.1)
+    }
+
+    fn distance_to_segment(&self, from: &Position, to: &Position) -> f64 {
+        let (dx, dy) = (*to.0 - *from.0, *to.1 - *from.1);
+        let length = dx * dx + dy * dy;
+        if length == 0.0 {
+            return self.distance(from);
+        }
+
+        // Project onto the segment, clamped to its ends.
+        let t = (((*self.0 - *from.0) * dx + (*self.1 - *from.1) * dy) / length).clamp(0.0, 1.0);
+        self.distance(&Position::new(*from.0 + t * dx, *from.1 + t * dy))
+    }
+}
+
+impl From<&Node> for Position {
+    fn from(node: &Node) -> Self {
+        Self::new(
+            f64::from(node.decimicro_lon) / 10_000_000.0,
+            f64::from(node.decimicro_lat) / 10_000_000.0,
+        )
+    }
 }
 
 impl std::fmt::Debug for Position {
@@ -50,12 +81,79 @@ impl Line {
         } else if tail.end() == self.end() {
             // If the current end matches the next end, extend in reverse
             self.0.extend(tail.0.iter().rev().skip(1));
+        } else if tail.start() == self.start() || tail.end() == self.start() {
+            // If the next line connects to the current start, turn around and extend from there
+            self.0.reverse();
+            self.extend(tail)?;
         } else {
             bail!("Linestrings do not form a continuous path");
         }
 
         Ok(())
     }
+
+    /// Collapse consecutive equal positions. A closed ring stays closed.
+    fn dedup(&mut self) {
+        self.0.dedup();
+        if self.0.len() == 1 {
+            self.0.push(self.0[0]);
+        }
+    }
+
+    /// Simplify the line with the Douglas-Peucker algorithm, dropping points closer than
+    /// `epsilon` to the simplified line. Start and end are always kept. A closed ring is split at
+    /// its point farthest from the start and not simplified below four points.
+    fn simplify(&self, epsilon: f64) -> Line {
+        fn mark(points: &[Position], epsilon: f64, keep: &mut [bool]) {
+            let (first, last) = (&points[0], &points[points.len() - 1]);
+            let farthest = points
+                .iter()
+                .enumerate()
+                .skip(1)
+                .take(points.len().saturating_sub(2))
+                .map(|(i, p)| (i, p.distance_to_segment(first, last)))
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+
+            if let Some((i, _)) = farthest.filter(|(_, distance)| *distance > epsilon) {
+                keep[i] = true;
+                mark(&points[..=i], epsilon, &mut keep[..=i]);
+                mark(&points[i..], epsilon, &mut keep[i..]);
+            }
+        }
+
+        let points = &self.0;
+        let mut keep = vec![false; points.len()];
+        keep[0] = true;
+        keep[points.len() - 1] = true;
+
+        if self.start() == self.end() {
+            // The chord of a closed ring is a single point, so split it in two.
+            let (split, _) = points
+                .iter()
+                .enumerate()
+                .max_by(|a, b| {
+                    a.1.distance(self.start())
+                        .total_cmp(&b.1.distance(self.start()))
+                })
+                .expect("line cannot be empty");
+            keep[split] = true;
+            mark(&points[..=split], epsilon, &mut keep[..=split]);
+            mark(&points[split..], epsilon, &mut keep[split..]);
+        } else {
+            mark(points, epsilon, &mut keep);
+        }
+
+        let simplified = points
+            .iter()
+            .zip(keep)
+            .filter_map(|(p, keep)| keep.then_some(*p))
+            .collect::<Vec<_>>();
+
+        if self.start() == self.end() && simplified.len() < 4 {
+            return self.clone();
+        }
+        Line(simplified)
+    }
 }
 
 impl std::fmt::Debug for Line {
@@ -80,290 +178,3662 @@ impl TryFrom<Vec<Position>> for Line {
 /// e.g., `multi_key_map::MultiKeyMap` a key can also refer to multiple entries, so this is
 /// effectively a multi-key-multi-value map.
 #[derive(Default, Debug)]
-pub struct MultiMap<K, V> {
+pub(crate) struct MultiMap<K, V> {
     m: HashMap<K, BTreeSet<V>>,
+    /// The keys of each value, so removing it only touches the sets it is in. Keys without
+    /// values are dropped from `m`.
+    keys: BTreeMap<V, Vec<K>>,
 }
 
-impl<K: Eq + Hash, V: Ord + Copy> MultiMap<K, V> {
+impl<K: Eq + Hash + Clone, V: Ord + Copy> MultiMap<K, V> {
     pub fn is_empty(&self) -> bool {
-        self.m.is_empty() || self.m.values().all(BTreeSet::is_empty)
+        self.m.is_empty()
     }
 
     pub fn insert(&mut self, key: K, value: V) {
-        self.m.entry(key).or_default().insert(value);
+        if self.m.entry(key.clone()).or_default().insert(value) {
+            self.keys.entry(value).or_default().push(key);
+        }
     }
 
     /// Remove a value from the map. This makes the value unreachable under any key it was added for.
     pub fn consume_one(&mut self, key: &K) -> Option<V> {
         let x = self.get(key).copied()?;
-
-        for xs in self.m.values_mut() {
-            xs.remove(&x);
-        }
+        self.remove(&x);
 
         Some(x)
     }
 
+    /// Remove `value` under all keys.
+    pub fn remove(&mut self, value: &V) {
+        for key in self.keys.remove(value).unwrap_or_default() {
+            if let Some(xs) = self.m.get_mut(&key) {
+                xs.remove(value);
+                if xs.is_empty() {
+                    self.m.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// The smallest value under `key`. Picking it regardless of the order values were inserted
+    /// or removed in keeps ring assembly reproducible across runs.
     pub fn get(&self, key: &K) -> Option<&V> {
-        self.m
-            .get(key)
-            .iter()
-            .next()
-            .and_then(|xs| xs.iter().next())
+        self.m.get(key)?.first()
     }
 }
 
-pub fn write(objs: &BTreeMap<OsmId, OsmObj>, out: impl io::Write) -> Result<()> {
-    // Use a buffered writer to amortize flushes.
-    let mut buffer = BufWriter::new(out);
+/// An axis-aligned bounding box in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BBox {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
 
-    for relation in objs.values().filter(|obj| filter::by_target(obj)) {
-        match to_feature(relation, objs) {
-            Ok(feature) => {
-                let serialized = feature.to_string();
-                writeln!(buffer, "{serialized}")?;
-            }
-            Err(e) => error!("{e}: {}", e.root_cause()),
+impl BBox {
+    /// Compute the bounding box of all positions in `geometry`.
+    #[must_use]
+    pub fn of(geometry: &geojson::Value) -> Option<Self> {
+        let mut bbox: Option<Self> = None;
+
+        for_each_position(geometry, &mut |p| {
+            let point = Self {
+                min_lon: p[0],
+                min_lat: p[1],
+                max_lon: p[0],
+                max_lat: p[1],
+            };
+            bbox = Some(bbox.map_or(point, |bbox| bbox.union(&point)));
+        });
+
+        bbox
+    }
+
+    /// The smallest box containing both `self` and `other`.
+    #[must_use]
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min_lon: self.min_lon.min(other.min_lon),
+            min_lat: self.min_lat.min(other.min_lat),
+            max_lon: self.max_lon.max(other.max_lon),
+            max_lat: self.max_lat.max(other.max_lat),
         }
     }
 
-    Ok(())
-}
+    /// Check whether `self` and `other` share any point.
+    #[must_use]
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.min_lon <= other.max_lon
+            && other.min_lon <= self.max_lon
+            && self.min_lat <= other.max_lat
+            && other.min_lat <= self.max_lat
+    }
 
-fn to_feature(obj: &OsmObj, all_objs: &BTreeMap<OsmId, OsmObj>) -> Result<geojson::GeoJson> {
-    let tags = obj.tags();
-    let name = {
-        let n = tags
-            .get("name")
-            .ok_or_else(|| anyhow!("'name' is missing"))?;
-        tags.get("name:prefix")
-            .map(|p| format!("{p} {n}"))
-            .unwrap_or(n.to_string())
-    };
-    let admin_level = tags
-        .get("admin_level")
-        .ok_or_else(|| anyhow!("'admin_level' is missing"))?;
-    let ars = tags
-        .get("de:regionalschluessel")
-        .ok_or_else(|| anyhow!("'de:regionalschluessel' is missing"))?;
-
-    let serde_json::Value::Object(properties) = json!({
-        "name": name,
-        "adminLevel":admin_level.parse::<u8>()?,
-        "ars": ars,
-    }) else {
-        todo!()
-    };
+    fn contains(&self, p: &Position) -> bool {
+        (self.min_lon..=self.max_lon).contains(&*p.0)
+            && (self.min_lat..=self.max_lat).contains(&*p.1)
+    }
 
-    let geometry = Geometry::new(
-        as_polygon(obj, all_objs)
-            .with_context(|| format!("cannot convert object '{name}' to polygon"))?,
-    );
+    /// Check whether any part of `geometry` lies within the box.
+    #[must_use]
+    pub fn intersects(&self, geometry: &geojson::Value) -> bool {
+        match geometry {
+            geojson::Value::Point(p) => self.contains(&Position::new(p[0], p[1])),
+            geojson::Value::MultiPoint(ps) => {
+                ps.iter().any(|p| self.contains(&Position::new(p[0], p[1])))
+            }
+            geojson::Value::LineString(ps) => self.intersects_line(ps),
+            geojson::Value::MultiLineString(lines) => {
+                lines.iter().any(|ps| self.intersects_line(ps))
+            }
+            geojson::Value::Polygon(rings) => self.intersects_polygon(rings),
+            geojson::Value::MultiPolygon(polygons) => {
+                polygons.iter().any(|rings| self.intersects_polygon(rings))
+            }
+            geojson::Value::GeometryCollection(geometries) => {
+                geometries.iter().any(|g| self.intersects(&g.value))
+            }
+        }
+    }
 
-    Ok(GeoJson::Feature(geojson::Feature {
-        id: Some(geojson::feature::Id::Number(
-            serde_json::value::Number::from(
-                obj.relation()
-                    .ok_or_else(|| anyhow!("'relation' is missing"))?
-                    .id
-                    .0,
-            ),
-        )),
-        geometry: Some(geometry),
-        properties: Some(properties),
-        ..geojson::Feature::default()
-    }))
-}
+    /// The corners of the box, counterclockwise from the south west.
+    fn corners(&self) -> [Position; 4] {
+        [
+            Position::new(self.min_lon, self.min_lat),
+            Position::new(self.max_lon, self.min_lat),
+            Position::new(self.max_lon, self.max_lat),
+            Position::new(self.min_lon, self.max_lat),
+        ]
+    }
 
-fn as_polygon(obj: &OsmObj, all_objs: &BTreeMap<OsmId, OsmObj>) -> Result<geojson::Value> {
-    let to_coords = |way: &Way| -> Option<Vec<Position>> {
-        way.nodes
+    /// Check whether a vertex of the line through `positions` lies in the box, or a segment
+    /// crosses its edges.
+    fn intersects_line(&self, positions: &[Vec<f64>]) -> bool {
+        let line = positions
             .iter()
-            .map(|node_id| {
-                let node = all_objs.get(&OsmId::Node(*node_id))?;
-                Some(Position::new(
-                    f64::from(node.node()?.decimicro_lon) / 10_000_000.0,
-                    f64::from(node.node()?.decimicro_lat) / 10_000_000.0,
-                ))
+            .map(|p| Position::new(p[0], p[1]))
+            .collect::<Vec<_>>();
+        let corners = self.corners();
+
+        line.iter().any(|p| self.contains(p))
+            || line.windows(2).any(|segment| {
+                (0..corners.len()).any(|i| {
+                    segments_intersect(
+                        (&segment[0], &segment[1]),
+                        (&corners[i], &corners[(i + 1) % corners.len()]),
+                    )
+                })
             })
-            .collect()
-    };
-
-    let linestrings = obj
-        .relation()
-        .ok_or_else(|| anyhow!("'relation' is missing"))?
-        .refs
-        .iter()
-        .filter_map(|child: &Ref| {
-            // todo treat 'inner' and contained relations as well
-            if matches!(child.role.as_str(), "outer") {
-                Some(to_coords(all_objs.get(&child.member)?.way()?)?)
-            } else {
-                None
-            }
-        })
-        .filter_map(|xs: Vec<_>| Line::try_from(xs).ok())
-        .collect::<Vec<_>>();
+    }
 
-    // todo report missing geometry or broken linering
-    let mut linering = create_continuous_linering(&linestrings)?;
+    fn intersects_polygon(&self, rings: &[Vec<Vec<f64>>]) -> bool {
+        let Some(outer) = rings.first() else {
+            return false;
+        };
+        let corner = self.corners()[0];
+
+        // Either the outer ring meets the box like a line, or the box lies in the polygon.
+        self.intersects_line(outer)
+            || ring_contains(
+                &outer
+                    .iter()
+                    .map(|p| Position::new(p[0], p[1]))
+                    .collect::<Vec<_>>(),
+                &corner,
+            )
+    }
+}
 
-    // respect right hand rule
-    if is_clockwise(&linering) {
-        linering.0.reverse();
+impl From<BBox> for geojson::Bbox {
+    fn from(bbox: BBox) -> Self {
+        vec![bbox.min_lon, bbox.min_lat, bbox.max_lon, bbox.max_lat]
     }
+}
 
-    Ok(geojson::Value::Polygon(vec![linering
-        .0
-        .iter()
-        .map(|p| vec![*p.0, *p.1])
-        .collect()]))
+/// An area of polygons, e.g. read from a `GeoJSON` file, to select features by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mask {
+    /// Polygons as lists of rings, the outer ring first.
+    polygons: Vec<Vec<Vec<Position>>>,
 }
 
-/// Create a continuous ring from line strings.
-fn create_continuous_linering(linestrings: &[Line]) -> Result<Line> {
-    if linestrings.is_empty() {
-        bail!("no linestrings")
+impl Mask {
+    /// Read the polygons of a `GeoJSON` geometry, feature or feature collection from `path`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file cannot be read, is no `GeoJSON` or contains no polygons.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let geojson = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read '{path}'"))?
+            .parse::<GeoJson>()
+            .with_context(|| format!("'{path}' is no valid GeoJSON"))?;
+
+        let geometries = match &geojson {
+            GeoJson::Geometry(geometry) => vec![geometry],
+            GeoJson::Feature(feature) => feature.geometry.iter().collect(),
+            GeoJson::FeatureCollection(collection) => collection
+                .features
+                .iter()
+                .filter_map(|f| f.geometry.as_ref())
+                .collect(),
+        };
+
+        let mask = Self {
+            polygons: geometries
+                .into_iter()
+                .flat_map(|geometry| polygons(&geometry.value))
+                .map(|rings| {
+                    rings
+                        .iter()
+                        .map(|ring| ring.iter().map(|p| Position::new(p[0], p[1])).collect())
+                        .collect()
+                })
+                .collect(),
+        };
+        if mask.polygons.is_empty() {
+            bail!("'{path}' contains no polygons");
+        }
+
+        Ok(mask)
     }
 
-    // Convert the endpoint positions to a hashable type (tuple) and build the index map
-    let mut endpoints = MultiMap::default();
-    for (i, linestring) in linestrings.iter().enumerate().skip(1) {
-        let start = Position::new(*linestring.start().0, *linestring.start().1);
-        let end = Position::new(*linestring.end().0, *linestring.end().1);
-        endpoints.insert(start, i);
-        endpoints.insert(end, i);
+    /// Check whether `p` lies inside any of the polygons, but not in one of their holes.
+    fn contains(&self, p: &Position) -> bool {
+        self.polygons.iter().any(|rings| {
+            rings.first().is_some_and(|outer| ring_contains(outer, p))
+                && !rings.iter().skip(1).any(|inner| ring_contains(inner, p))
+        })
     }
 
-    // Start from the first linestring
-    let first_index = 0;
-    let mut continuous_line = linestrings[first_index].clone();
+    /// Check whether the centroid of a polygonal `geometry` lies inside the mask, or any
+    /// position of other geometries.
+    #[must_use]
+    pub fn selects(&self, geometry: &geojson::Value) -> bool {
+        if let Some([lon, lat]) = centroid(geometry) {
+            return self.contains(&Position::new(lon, lat));
+        }
 
-    while !endpoints.is_empty() {
-        let current_end_key = continuous_line.end();
+        let mut selected = false;
+        for_each_position(geometry, &mut |p| {
+            selected |= self.contains(&Position::new(p[0], p[1]));
+        });
+        selected
+    }
+}
 
-        let Some(next_index) = endpoints.consume_one(current_end_key) else {
-            bail!("No more matching linestrings found")
-        };
+/// Mean earth radius in kilometers.
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+/// Calculate the area of a polygonal geometry on a sphere in square kilometers. Outer rings count
+/// positive and inner rings negative regardless of their winding; other geometries have no area.
+fn area_km2(geometry: &geojson::Value) -> f64 {
+    // Area enclosed by a ring (Chamberlain & Duquette, "Some Algorithms for Polygons on a
+    // Sphere", 2007), signed by its winding.
+    let ring_area = |ring: &[Vec<f64>]| -> f64 {
+        edges(ring)
+            .map(|(from, to)| {
+                (to[0] - from[0]).to_radians()
+                    * (2.0 + from[1].to_radians().sin() + to[1].to_radians().sin())
+            })
+            .sum::<f64>()
+            * EARTH_RADIUS_KM.powi(2)
+            / 2.0
+    };
+    let polygon_area = |rings: &Vec<Vec<Vec<f64>>>| -> f64 {
+        let mut rings = rings.iter().map(|ring| ring_area(ring).abs());
+        let outer = rings.next().unwrap_or_default();
+        outer - rings.sum::<f64>()
+    };
 
-        let next_linestring = &linestrings[next_index];
-        continuous_line.extend(next_linestring)?;
+    match geometry {
+        geojson::Value::Polygon(rings) => polygon_area(rings),
+        geojson::Value::MultiPolygon(polygons) => polygons.iter().map(polygon_area).sum(),
+        geojson::Value::GeometryCollection(geometries) => {
+            geometries.iter().map(|g| area_km2(&g.value)).sum()
+        }
+        _ => 0.0,
     }
+}
 
-    // Check if the start and end positions match to close the loop
-    if continuous_line.start() != continuous_line.end() {
-        bail!("Ends of the linestrings don't form a ring");
+/// The polygons of a polygonal geometry, each as list of rings with the outer ring first.
+fn polygons(geometry: &geojson::Value) -> Vec<&[Vec<Vec<f64>>]> {
+    match geometry {
+        geojson::Value::Polygon(rings) => vec![rings],
+        geojson::Value::MultiPolygon(polygons) => polygons.iter().map(Vec::as_slice).collect(),
+        geojson::Value::GeometryCollection(geometries) => {
+            geometries.iter().flat_map(|g| polygons(&g.value)).collect()
+        }
+        _ => vec![],
     }
+}
 
-    Ok(continuous_line)
+/// The rings of the polygons in `geometry` as line strings, a single `LineString` for a polygon
+/// without holes. Other geometries are returned unchanged.
+fn to_lines(geometry: geojson::Value) -> geojson::Value {
+    match geometry {
+        geojson::Value::Polygon(mut rings) if rings.len() == 1 => {
+            geojson::Value::LineString(rings.remove(0))
+        }
+        geojson::Value::Polygon(rings) => geojson::Value::MultiLineString(rings),
+        geojson::Value::MultiPolygon(polygons) => {
+            geojson::Value::MultiLineString(polygons.into_iter().flatten().collect())
+        }
+        geometry => geometry,
+    }
 }
 
-/// Calculate the orientation of the ring
-fn is_clockwise(ring: &Line) -> bool {
-    // Calculate the signed area under the curve (Shoelace formula).
+/// The segments of a ring from each position to the next, wrapping around at the end.
+fn edges(ring: &[Vec<f64>]) -> impl Iterator<Item = (&Vec<f64>, &Vec<f64>)> {
+    ring.iter().zip(ring.iter().cycle().skip(1))
+}
 
-    let cur = ring.0.iter();
-    let next = ring.0.iter().chain(ring.0.iter()).skip(1);
-    cur.zip(next)
-        .map(|(c, n)| *((n.0 - c.0) * (n.1 + c.1)))
+/// Planar area of a ring in square degrees, positive if it winds counterclockwise.
+fn signed_area(ring: &[Vec<f64>]) -> f64 {
+    edges(ring)
+        .map(|(from, to)| from[0] * to[1] - to[0] * from[1])
         .sum::<f64>()
-        > 0.0
+        / 2.0
 }
 
-#[cfg(test)]
-mod test {
-    use super::{Line, Position};
+/// Calculate the area-weighted centroid of a polygonal geometry. Note that it can lie outside of
+/// concave polygons.
+fn centroid(geometry: &geojson::Value) -> Option<[f64; 2]> {
+    let (mut area, mut lon, mut lat) = (0.0, 0.0, 0.0);
+
+    for rings in polygons(geometry) {
+        for (i, ring) in rings.iter().enumerate() {
+            let ring_area = signed_area(ring);
+            // Outer rings add and inner rings subtract, whatever their winding.
+            let sign = if i == 0 { 1.0 } else { -1.0 } * ring_area.signum();
+
+            for (from, to) in edges(ring) {
+                let cross = from[0] * to[1] - to[0] * from[1];
+                lon += sign * (from[0] + to[0]) * cross / 6.0;
+                lat += sign * (from[1] + to[1]) * cross / 6.0;
+            }
+            area += sign * ring_area;
+        }
+    }
 
-    #[test]
-    fn extend() {
-        let p1 = Position::new(0., 0.);
-        let p2 = Position::new(1., 0.);
+    (area != 0.0).then(|| [lon / area, lat / area])
+}
 
-        {
-            let mut l = Line::try_from(vec![p1, p2]).unwrap();
-            l.extend(&Line::try_from(vec![p2, p1]).unwrap()).unwrap();
-            assert_eq!(l, Line::try_from(vec![p1, p2, p1]).unwrap());
+/// Find a point inside a polygonal geometry: the middle of the widest interior section of a
+/// horizontal line through the middle of its largest polygon.
+fn point_on_surface(geometry: &geojson::Value) -> Option<[f64; 2]> {
+    let rings = polygons(geometry).into_iter().max_by(|a, b| {
+        let area = |rings: &[Vec<Vec<f64>>]| rings.first().map_or(0.0, |r| signed_area(r).abs());
+        area(a).total_cmp(&area(b))
+    })?;
+
+    let (min_lat, max_lat) = rings
+        .first()?
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), p| {
+            (min.min(p[1]), max.max(p[1]))
+        });
+    let lat = f64::midpoint(min_lat, max_lat);
+
+    // Longitudes where the line crosses any ring, so consecutive pairs enclose the interior.
+    let mut crossings = rings
+        .iter()
+        .flat_map(|ring| ring.windows(2))
+        .filter(|segment| (segment[0][1] > lat) != (segment[1][1] > lat))
+        .map(|segment| {
+            let (from, to) = (&segment[0], &segment[1]);
+            from[0] + (to[0] - from[0]) * (lat - from[1]) / (to[1] - from[1])
+        })
+        .collect::<Vec<_>>();
+    crossings.sort_by(f64::total_cmp);
+
+    crossings
+        .chunks_exact(2)
+        .max_by(|a, b| (a[1] - a[0]).total_cmp(&(b[1] - b[0])))
+        .map(|section| [f64::midpoint(section[0], section[1]), lat])
+}
+
+/// Call `f` for each position in `geometry`.
+fn for_each_position(geometry: &geojson::Value, f: &mut impl FnMut(&[f64])) {
+    match geometry {
+        geojson::Value::Point(p) => f(p),
+        geojson::Value::MultiPoint(ps) | geojson::Value::LineString(ps) => {
+            for p in ps {
+                f(p);
+            }
+        }
+        geojson::Value::MultiLineString(lines) | geojson::Value::Polygon(lines) => {
+            for p in lines.iter().flatten() {
+                f(p);
+            }
+        }
+        geojson::Value::MultiPolygon(polygons) => {
+            for p in polygons.iter().flatten().flatten() {
+                f(p);
+            }
+        }
+        geojson::Value::GeometryCollection(geometries) => {
+            for g in geometries {
+                for_each_position(&g.value, f);
+            }
         }
+    }
+}
 
-        {
-            let mut l = Line::try_from(vec![p1, p2]).unwrap();
-            l.extend(&Line::try_from(vec![p1, p2]).unwrap()).unwrap();
-            assert_eq!(l, Line::try_from(vec![p1, p2, p1]).unwrap());
+/// Call `f` with each position in `geometry` to modify it.
+fn for_each_position_mut(geometry: &mut geojson::Value, f: &mut impl FnMut(&mut Vec<f64>)) {
+    match geometry {
+        geojson::Value::Point(p) => f(p),
+        geojson::Value::MultiPoint(ps) | geojson::Value::LineString(ps) => {
+            for p in ps {
+                f(p);
+            }
+        }
+        geojson::Value::MultiLineString(lines) | geojson::Value::Polygon(lines) => {
+            for p in lines.iter_mut().flatten() {
+                f(p);
+            }
+        }
+        geojson::Value::MultiPolygon(polygons) => {
+            for p in polygons.iter_mut().flatten().flatten() {
+                f(p);
+            }
+        }
+        geojson::Value::GeometryCollection(geometries) => {
+            for g in geometries {
+                for_each_position_mut(&mut g.value, f);
+            }
         }
     }
+}
 
-    #[test]
-    fn is_clockwise() {
-        use super::is_clockwise;
+/// Swap longitude and latitude in the geometry, bounding box and center of `feature`.
+fn swap_coordinates(feature: &mut geojson::Feature) {
+    if let Some(geometry) = &mut feature.geometry {
+        for_each_position_mut(&mut geometry.value, &mut |p| p.swap(0, 1));
+    }
+    if let Some(bbox) = feature.bbox.as_mut().filter(|bbox| bbox.len() == 4) {
+        bbox.swap(0, 1);
+        bbox.swap(2, 3);
+    }
+    let center = feature
+        .properties
+        .as_mut()
+        .and_then(|p| p.get_mut("center"));
+    if let Some(serde_json::Value::Array(center)) = center {
+        center.swap(0, 1);
+    }
+}
 
-        // Points in the four quadrants of a cartesian coordinate system. Numbering here is
-        // counterclockwise.
-        let q1 = Position::new(1.0, 1.0);
-        let q2 = Position::new(1.0, -1.0);
-        let q3 = Position::new(-1.0, -1.0);
-        let q4 = Position::new(-1.0, 1.0);
+impl std::str::FromStr for BBox {
+    type Err = anyhow::Error;
 
-        // Segments with two elements are both clock- and counterclockwise.
-        assert!(!is_clockwise(&Line::try_from(vec![q1, q2]).unwrap()));
-        assert!(!is_clockwise(&Line::try_from(vec![q2, q1]).unwrap()));
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s
+            .split(',')
+            .map(|x| x.trim().parse::<f64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .context("bounding box values must be numbers")?;
 
-        assert!(is_clockwise(&Line::try_from(vec![q1, q2, q3, q4]).unwrap()));
-        assert!(!is_clockwise(
-            &Line::try_from(vec![q4, q3, q2, q1]).unwrap()
-        ));
+        let [min_lon, min_lat, max_lon, max_lat] = values[..] else {
+            bail!("expected bounding box as 'minlon,minlat,maxlon,maxlat'");
+        };
+
+        if min_lon > max_lon || min_lat > max_lat {
+            bail!("bounding box minimum must not exceed its maximum");
+        }
+
+        Ok(Self {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        })
     }
+}
 
-    #[test]
-    fn create_continuous_linering() {
-        use super::create_continuous_linering;
+/// Settings for the conversion of relations to features.
+#[derive(Debug, Default, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Options {
+    /// Distance in degrees within which way endpoints are treated as coincident.
+    pub snap_tolerance: f64,
 
-        let p1 = Position::new(0., 0.);
-        let p2 = Position::new(1., 0.);
-        let p3 = Position::new(2., 0.);
+    /// Only emit features whose geometry intersects this box.
+    pub bbox: Option<BBox>,
 
-        {
-            let l = Line::try_from(vec![p1, p1]).unwrap();
-            assert_eq!(create_continuous_linering(&vec![l.clone()]).unwrap(), l);
-        }
+    /// Only emit features selected by this mask, see `Mask::selects`.
+    pub clip_mask: Option<Mask>,
 
-        {
-            let l = Line::try_from(vec![p1, p2, p1]).unwrap();
-            assert_eq!(create_continuous_linering(&vec![l.clone()]).unwrap(), l);
+    /// Only emit features with an area of at least this many square kilometres. Lines and
+    /// points have no area.
+    pub min_area: Option<f64>,
+
+    /// Emit coordinates as latitude, longitude instead of the other way round, which is no valid
+    /// `GeoJSON`.
+    pub lat_lon: bool,
+
+    /// Number of decimal places to round output coordinates to.
+    pub precision: Option<u8>,
+
+    /// Maximum number of features to emit.
+    pub limit: Option<usize>,
+
+    /// Tag whose value is emitted as the `ars` property.
+    pub id_tag: Option<String>,
+
+    /// What to take the feature id from.
+    pub id_source: IdSource,
+
+    /// Skip features with self-intersecting rings instead of only reporting them.
+    pub skip_invalid: bool,
+
+    /// Emit a point guaranteed to lie inside the polygon as center instead of its centroid.
+    pub point_on_surface: bool,
+    /// Tolerance in degrees for simplifying rings with Douglas-Peucker.
+    pub simplify: Option<f64>,
+    /// Log the number of features converted every now and then.
+    pub progress: bool,
+    /// Capacity in bytes of the buffer in front of the output, the default of `BufWriter` if
+    /// unset.
+    pub buffer_size: Option<usize>,
+
+    /// Fail on the first relation which cannot be converted instead of skipping it.
+    pub strict: bool,
+
+    /// Emit the outer ways of relations whose outer ring cannot be closed as multi line string
+    /// instead of skipping them.
+    pub include_incomplete: bool,
+
+    /// How to handle `admin_level` values which are no plain number.
+    pub admin_level_fallback: AdminLevelFallback,
+
+    /// Buffer all features and emit them in this order instead of as they are converted.
+    pub sort_by: Option<SortBy>,
+
+    /// Languages to take the name from, most preferred first, e.g. "en" for `name:en`. "name"
+    /// stands for the plain `name` tag, which is the fallback in any case.
+    pub name_langs: Vec<String>,
+
+    /// Indent the JSON written by `write_collection` and `write_files`.
+    pub pretty: bool,
+
+    /// Name the coordinate reference system in a `crs` member of the collection written by
+    /// `write_collection`. Current `GeoJSON` dropped this member, so strict parsers may reject it.
+    pub emit_crs: bool,
+
+    /// Emit the `name:prefix` as part of the name instead of in a separate `display_name`.
+    pub merge_name_prefix: bool,
+
+    /// Additional tags to copy into the properties.
+    pub properties: Vec<String>,
+
+    /// Copy all tags into the properties. Properties derived from tags take precedence.
+    pub all_tags: bool,
+
+    /// Tags never to copy into the properties, neither from `properties` nor with `all_tags`.
+    pub exclude_tags: Vec<String>,
+
+    /// Tags to copy into the properties as JSON numbers if their value parses as one.
+    pub numeric_tags: Vec<String>,
+
+    /// Skip the geometry and emit features with properties only. Without geometry they never pass
+    /// `bbox`, `clip_mask` or `min_area`.
+    pub properties_only: bool,
+
+    /// Emit the rings of polygons as line strings, e.g. to style borders without filling them.
+    /// Properties like `area_km2` and `center` still describe the polygon.
+    pub as_lines: bool,
+}
+
+impl Options {
+    /// Wrap `out` in a buffer with a capacity of `buffer_size`.
+    pub fn buffer<W: io::Write>(&self, out: W) -> BufWriter<W> {
+        match self.buffer_size {
+            Some(capacity) => BufWriter::with_capacity(capacity, out),
+            None => BufWriter::new(out),
         }
+    }
 
-        {
-            let l = Line::try_from(vec![p1, p2, p3, p1]).unwrap();
-            assert_eq!(create_continuous_linering(&vec![l.clone()]).unwrap(), l);
+    fn round(&self, x: f64) -> f64 {
+        match self.precision {
+            Some(precision) => {
+                let factor = 10_f64.powi(i32::from(precision));
+                (x * factor).round() / factor
+            }
+            None => x,
         }
+    }
+}
 
-        {
-            let l1 = Line::try_from(vec![p1, p2]).unwrap();
-            let l2 = Line::try_from(vec![p2, p1]).unwrap();
-            let l3 = Line::try_from(vec![p1, p2, p1]).unwrap();
-            assert_eq!(create_continuous_linering(&vec![l1, l2]).unwrap(), l3);
+/// How to handle malformed `admin_level` values like "8;9".
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AdminLevelFallback {
+    /// Emit the first number in the value, or else the value as string.
+    #[default]
+    First,
+    /// Emit the value as string.
+    Raw,
+    /// Fail to convert the object.
+    Fail,
+}
+
+impl std::str::FromStr for AdminLevelFallback {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "first" => Self::First,
+            "raw" => Self::Raw,
+            "fail" => Self::Fail,
+            _ => bail!("expected 'first', 'raw' or 'fail'"),
+        })
+    }
+}
+
+/// What to take the feature id from, see `Options::id_source`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IdSource {
+    /// The OSM id, as number.
+    #[default]
+    Relation,
+    /// The `ars` property, falling back to the OSM id without it.
+    Ars,
+    /// The `name` property.
+    Name,
+}
+
+impl std::str::FromStr for IdSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "relation" => Self::Relation,
+            "ars" => Self::Ars,
+            "name" => Self::Name,
+            _ => bail!("expected 'relation', 'ars' or 'name'"),
+        })
+    }
+}
+
+/// Property to order features by with `Options::sort_by`. Ties are broken by id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// The OSM id, regardless of the type of object.
+    Id,
+    Name,
+    /// The admin level, features without a numeric one last.
+    AdminLevel,
+}
+
+impl std::str::FromStr for SortBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "id" => Self::Id,
+            "name" => Self::Name,
+            "admin_level" => Self::AdminLevel,
+            _ => bail!("expected 'id', 'name' or 'admin_level'"),
+        })
+    }
+}
+
+impl SortBy {
+    /// Sort `features` by the key selected.
+    fn sort(self, features: &mut [geojson::Feature]) {
+        fn id(feature: &geojson::Feature) -> u64 {
+            match &feature.id {
+                Some(geojson::feature::Id::Number(n)) => n.as_u64().unwrap_or(u64::MAX),
+                _ => u64::MAX,
+            }
+        }
+        fn text<'a>(feature: &'a geojson::Feature, key: &str) -> &'a str {
+            feature
+                .property(key)
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or_default()
         }
 
-        {
-            let l1 = Line::try_from(vec![p1, p2]).unwrap();
-            let l2 = Line::try_from(vec![p1, p2]).unwrap();
-            let l3 = Line::try_from(vec![p1, p2, p1]).unwrap();
-            assert_eq!(create_continuous_linering(&vec![l1, l2]).unwrap(), l3);
+        match self {
+            Self::Id => features.sort_by(|a, b| {
+                id(a)
+                    .cmp(&id(b))
+                    .then_with(|| text(a, "osm_type").cmp(text(b, "osm_type")))
+            }),
+            Self::Name => {
+                features
+                    .sort_by(|a, b| text(a, "name").cmp(text(b, "name")).then(id(a).cmp(&id(b))));
+            }
+            Self::AdminLevel => features.sort_by_key(|f| {
+                let level = f.property("adminLevel").and_then(serde_json::Value::as_u64);
+                (level.unwrap_or(u64::MAX), id(f))
+            }),
         }
     }
+}
 
-    mod multi_map {
-        use super::super::MultiMap;
+/// Number of relations converted in parallel before their features are handed on.
+const CHUNK_SIZE: usize = 1024;
 
-        #[test]
-        fn get() {
-            let mut m = MultiMap::default();
-            m.insert(1, 12);
-            m.insert(2, 12);
+/// Number of features written and of relations skipped because they could not be converted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub written: usize,
+    pub skipped: usize,
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Wrote {} features, skipped {} (geometry errors)",
+            self.written, self.skipped
+        )
+    }
+}
+
+/// Convert the objects in `objs` matching `pred` to features. Objects which cannot be converted
+/// are logged, skipped and counted in `skipped`, unless `options.strict` is set. Then the first
+/// of them is yielded as error naming the relation.
+///
+/// Conversion runs in parallel on chunks of relations, but features are yielded ordered by relation
+/// id. With `options.sort_by`, all features are converted before the first is yielded.
+pub(crate) fn features<'a>(
+    objs: &'a Objects,
+    pred: impl Fn(&OsmObj) -> bool + 'a,
+    options: &'a Options,
+    skipped: &'a mut usize,
+) -> impl Iterator<Item = Result<geojson::Feature>> + 'a {
+    let mut progress = util::Progress::new("Converted features", 1000, options.progress);
+    let mut seen = HashSet::new();
+    let positions = node_positions(objs);
+    let matching = util::matching(objs, pred);
+    let chunks = matching
+        .chunks(CHUNK_SIZE)
+        .map(<[_]>::to_vec)
+        .collect::<Vec<_>>();
+
+    let features = chunks
+        .into_iter()
+        .flat_map(move |chunk| {
+            chunk
+                .par_iter()
+                .map(|relation| {
+                    let position = |id| positions.get(&id).copied();
+                    (relation.id(), convert(relation, objs, &position, options))
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter_map(move |(id, feature)| match feature {
+            Ok(feature) => Some(Ok(feature)),
+            Err(e) if options.strict => Some(Err(
+                e.context(format!("cannot convert relation {}", id.inner_id()))
+            )),
+            Err(e) => {
+                *skipped += 1;
+                error!("{e}: {}", e.root_cause());
+                None
+            }
+        })
+        .filter(|feature| match (feature, &options.bbox) {
+            (Ok(feature), Some(bbox)) => feature
+                .geometry
+                .as_ref()
+                .is_some_and(|g| bbox.intersects(&g.value)),
+            _ => true,
+        })
+        .filter(|feature| match (feature, &options.clip_mask) {
+            (Ok(feature), Some(mask)) => feature
+                .geometry
+                .as_ref()
+                .is_some_and(|g| mask.selects(&g.value)),
+            _ => true,
+        })
+        .filter(|feature| match (feature, options.min_area) {
+            (Ok(feature), Some(min_area)) => {
+                // The property keeps the area of polygons emitted as lines.
+                let area = feature
+                    .property("area_km2")
+                    .and_then(serde_json::Value::as_f64)
+                    .unwrap_or(0.0);
+                if area < min_area {
+                    debug!(
+                        "dropping feature {:?}: area of {area} km² is below {min_area} km²",
+                        feature.id
+                    );
+                }
+                area >= min_area
+            }
+            _ => true,
+        })
+        .filter(move |feature| match feature {
+            Ok(feature) => is_new(feature, &mut seen),
+            Err(_) => true,
+        });
+
+    let features: Box<dyn Iterator<Item = Result<geojson::Feature>> + 'a> = match options.sort_by {
+        Some(sort_by) => match features.collect::<Result<Vec<_>>>() {
+            Ok(mut features) => {
+                sort_by.sort(&mut features);
+                Box::new(features.into_iter().map(Ok))
+            }
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        },
+        None => Box::new(features),
+    };
+
+    features
+        .take(options.limit.unwrap_or(usize::MAX))
+        .inspect(move |_| progress.tick())
+        .map(move |feature| {
+            // Only swap now, as the filters above expect longitude first.
+            feature.map(|mut feature| {
+                if options.lat_lon {
+                    swap_coordinates(&mut feature);
+                }
+                feature
+            })
+        })
+}
+
+/// Whether the id of `feature` is not in `seen` yet, and add it. Relations can share an id, e.g.
+/// an ARS, but each id is written once. Features without id are always new.
+fn is_new(feature: &geojson::Feature, seen: &mut HashSet<String>) -> bool {
+    let Some(id) = &feature.id else {
+        return true;
+    };
+    // Compare the JSON, which tells the string "1" from the number 1.
+    let new = seen.insert(serde_json::to_string(id).expect("ids serialize"));
+    if !new {
+        debug!("skipping feature {id:?}, whose id was written before");
+    }
+    new
+}
+
+/// Write a feature for each object in `objs` matching `pred`, one per line.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails or, with `options.strict`, if an object cannot be converted.
+pub fn write(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    out: impl io::Write,
+    options: &Options,
+) -> Result<Summary> {
+    write_records(objs, pred, out, options, "")
+}
+
+/// Write a feature for each object in `objs` matching `pred` as `GeoJSON` text sequence
+/// (RFC 8142), i.e. each prefixed with the record separator and followed by a line feed.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails or, with `options.strict`, if an object cannot be converted.
+pub fn write_seq(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    out: impl io::Write,
+    options: &Options,
+) -> Result<Summary> {
+    write_records(objs, pred, out, options, "\x1e")
+}
+
+/// Write the properties of each object in `objs` matching `pred` as JSON object, one per line.
+/// Set `options.properties_only` to skip converting the geometries, which are not written anyway.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails or, with `options.strict`, if an object cannot be converted.
+pub fn write_properties(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    out: impl io::Write,
+    options: &Options,
+) -> Result<Summary> {
+    // Use a buffered writer to amortize flushes.
+    let mut buffer = options.buffer(out);
+    let mut summary = Summary::default();
+
+    for feature in features(objs, pred, options, &mut summary.skipped) {
+        let properties = feature?.properties.unwrap_or_default();
+        writeln!(buffer, "{}", serde_json::Value::Object(properties))?;
+        summary.written += 1;
+    }
+
+    Ok(summary)
+}
+
+fn write_records(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    out: impl io::Write,
+    options: &Options,
+    prefix: &str,
+) -> Result<Summary> {
+    // Use a buffered writer to amortize flushes.
+    let mut buffer = options.buffer(out);
+    let mut summary = Summary::default();
+
+    for feature in features(objs, pred, options, &mut summary.skipped) {
+        writeln!(buffer, "{prefix}{}", feature?)?;
+        summary.written += 1;
+    }
+
+    Ok(summary)
+}
+
+/// Write each feature for the objects in `objs` matching `pred` to its own file in `dir`, named
+/// after its `ars` property or else its id. If several features get the same name, only the
+/// first is written and the others are logged and skipped. Finally `manifest.json` lists the
+/// file, id, name, `ars` and bbox of each written feature, in the order they were written.
+///
+/// # Errors
+///
+/// Fails if a file cannot be written or, with `options.strict`, if an object cannot be converted.
+pub fn write_files(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    dir: &Path,
+    options: &Options,
+) -> Result<Summary> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut summary = Summary::default();
+    let mut names = HashSet::new();
+    let mut manifest = vec![];
+
+    for feature in features(objs, pred, options, &mut summary.skipped) {
+        let feature = feature?;
+        let name = match (feature.property("ars"), &feature.id) {
+            (Some(serde_json::Value::String(ars)), _) => ars.clone(),
+            (_, Some(geojson::feature::Id::Number(id))) => id.to_string(),
+            (_, Some(geojson::feature::Id::String(id))) => id.clone(),
+            (_, None) => bail!("feature without 'ars' or id"),
+        }
+        // Keep tag values from escaping `dir`.
+        .replace(['/', '\\'], "_");
+
+        if !names.insert(name.clone()) {
+            error!(
+                "skipping feature {:?}: '{name}.geojson' was already written",
+                feature.id
+            );
+            continue;
+        }
+
+        let file_name = format!("{name}.geojson");
+        let mut file = BufWriter::new(std::fs::File::create(dir.join(&file_name))?);
+        writeln!(file, "{}", to_json(&feature, options.pretty)?)?;
+        summary.written += 1;
+
+        let property = |key| feature.property(key).cloned().unwrap_or_default();
+        manifest.push(serde_json::json!({
+            "file": file_name,
+            "id": feature.id,
+            "name": property("name"),
+            "ars": property("ars"),
+            "bbox": feature.bbox,
+        }));
+    }
+
+    let mut file = BufWriter::new(std::fs::File::create(dir.join("manifest.json"))?);
+    if options.pretty {
+        serde_json::to_writer_pretty(&mut file, &manifest)?;
+    } else {
+        serde_json::to_writer(&mut file, &manifest)?;
+    }
+    writeln!(file)?;
+
+    Ok(summary)
+}
+
+/// Write a feature for each relation in the PBF files at `paths` matching `pred`, one per line.
+/// This streams the files one after another with `util::for_each_relation` instead of loading
+/// all relations and their dependencies into memory first, so features are written in the order
+/// they complete. Members need to be in the same file as their relation, and member relations
+/// are not followed. A relation contained in several files is only written from the first.
+///
+/// # Errors
+///
+/// Fails if the file cannot be read, writing to `out` fails or, with `options.strict`, if an
+/// object cannot be converted.
+pub fn write_streaming(
+    paths: &[PathBuf],
+    pred: impl Fn(&OsmObj) -> bool,
+    out: impl io::Write,
+    options: &Options,
+) -> Result<Summary> {
+    // Use a buffered writer to amortize flushes.
+    let mut buffer = options.buffer(out);
+    let mut summary = Summary::default();
+
+    let mut remaining = options.limit.unwrap_or(usize::MAX);
+    // Features are converted one relation at a time, so count them here.
+    let mut progress = util::Progress::new("Converted features", 1000, options.progress);
+    let per_relation = Options {
+        progress: false,
+        ..options.clone()
+    };
+    // In contrast to loading, streaming doesn't merge the files.
+    let mut seen = HashSet::new();
+    // Features are converted one relation at a time, so `features` cannot tell repeated ids.
+    let mut seen_ids = HashSet::new();
+
+    for path in paths {
+        let scan = util::with_progress(&pred, options.progress);
+        util::for_each_relation(path, scan, |objs| {
+            let mut fresh = HashSet::new();
+            for obj in util::matching(objs, &pred) {
+                if seen.insert(obj.id()) {
+                    fresh.insert(obj.id());
+                } else {
+                    debug!(
+                        "skipping {:?} from {path:?}, which was read before",
+                        obj.id()
+                    );
+                }
+            }
+
+            // Files are still read to the end, but nothing is converted once the limit is hit.
+            let pred = |obj: &OsmObj| fresh.contains(&obj.id());
+            let features = features(objs, pred, &per_relation, &mut summary.skipped)
+                .filter(|feature| feature.as_ref().map_or(true, |f| is_new(f, &mut seen_ids)));
+            for feature in features.take(remaining) {
+                writeln!(buffer, "{}", feature?)?;
+                summary.written += 1;
+                remaining -= 1;
+                progress.tick();
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(summary)
+}
+
+/// Write the features for all objects in `objs` matching `pred` as a single
+/// `FeatureCollection` with the bounding box of all of them. This holds all features in memory
+/// until the end.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails or, with `options.strict`, if an object cannot be converted.
+pub fn write_collection(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    out: impl io::Write,
+    options: &Options,
+) -> Result<Summary> {
+    let mut summary = Summary::default();
+    let mut bbox: Option<BBox> = None;
+    let features = features(objs, pred, options, &mut summary.skipped)
+        .inspect(|feature| {
+            let geometry = feature.as_ref().ok().and_then(|f| f.geometry.as_ref());
+            if let Some(b) = geometry.and_then(|g| BBox::of(&g.value)) {
+                bbox = Some(bbox.map_or(b, |bbox| bbox.union(&b)));
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    summary.written = features.len();
+
+    let foreign_members = options.emit_crs.then(|| {
+        // Unlike CRS84, EPSG:4326 puts latitude first.
+        let name = if options.lat_lon {
+            "urn:ogc:def:crs:EPSG::4326"
+        } else {
+            "urn:ogc:def:crs:OGC:1.3:CRS84"
+        };
+        let crs = serde_json::json!({"type": "name", "properties": {"name": name}});
+        geojson::JsonObject::from_iter([("crs".to_string(), crs)])
+    });
+
+    let collection = GeoJson::FeatureCollection(geojson::FeatureCollection {
+        bbox: bbox.map(Into::into),
+        features,
+        foreign_members,
+    });
+
+    // Use a buffered writer to amortize flushes.
+    let mut buffer = options.buffer(out);
+    writeln!(buffer, "{}", to_json(&collection, options.pretty)?)?;
+
+    Ok(summary)
+}
+
+/// Serialize `value` as compact JSON, or indented if `pretty` is set.
+fn to_json(value: &impl serde::Serialize, pretty: bool) -> Result<String> {
+    Ok(if pretty {
+        serde_json::to_string_pretty(value)?
+    } else {
+        serde_json::to_string(value)?
+    })
+}
+
+/// Convert an object to a feature with its OSM id, and its OSM type, name, name with `name:prefix`
+/// as `display_name`, admin level, the value of `options.id_tag` and the tags in
+/// `options.properties` (if present) as properties, plus all other tags with `options.all_tags`,
+/// except for those in `options.exclude_tags`.
+/// Relations get their boundary as polygon geometry, or a multi polygon if their outer ways form
+/// several rings, closed ways a polygon, open ways a line string and nodes a point. `all_objs`
+/// needs to contain the members of relations and the nodes of ways.
+///
+/// # Errors
+///
+/// Fails if required tags or nodes are missing or the members of a relation do not form a ring.
+pub fn to_feature(obj: &OsmObj, all_objs: &Objects, options: &Options) -> Result<geojson::Feature> {
+    convert(obj, all_objs, &node_in(all_objs), options)
+}
+
+/// Look up node positions in `objs`.
+fn node_in(objs: &Objects) -> impl Fn(NodeId) -> Option<Position> + '_ {
+    |id| objs.get(&OsmId::Node(id))?.node().map(Position::from)
+}
+
+/// Collect the positions of all nodes in `objs`. Looking them up here is faster than in `objs`
+/// when converting many relations, especially as neighbouring relations share nodes.
+pub(crate) fn node_positions(objs: &Objects) -> HashMap<NodeId, Position> {
+    objs.values()
+        .filter_map(OsmObj::node)
+        .map(|node| (node.id, Position::from(node)))
+        .collect()
+}
+
+/// The type of the object with `id`. Ids are only unique per type, so it is emitted alongside
+/// the feature id.
+fn osm_type(id: OsmId) -> &'static str {
+    match id {
+        OsmId::Node(_) => "node",
+        OsmId::Way(_) => "way",
+        OsmId::Relation(_) => "relation",
+    }
+}
+
+/// The `adminLevel` property for the tag `value`. Values which are no plain number are handled
+/// as selected by `fallback`, with a warning.
+fn parse_admin_level(value: &str, fallback: AdminLevelFallback) -> Result<serde_json::Value> {
+    if let Ok(level) = value.parse::<u8>() {
+        return Ok(level.into());
+    }
+
+    let level = match fallback {
+        AdminLevelFallback::Fail => bail!("'admin_level' is '{value}' and no number"),
+        AdminLevelFallback::First => filter::parse_admin_level(value).map(Into::into),
+        AdminLevelFallback::Raw => None,
+    }
+    .unwrap_or_else(|| value.into());
+    warn!("'admin_level' is '{value}' and no number, using {level} instead");
+
+    Ok(level)
+}
+
+/// The name in the first of `langs` present in `tags`, or else the plain `name`.
+fn name_of<'a>(tags: &'a Tags, langs: &[String]) -> Option<&'a str> {
+    langs
+        .iter()
+        .find_map(|lang| match lang.as_str() {
+            "name" => tags.get("name"),
+            lang => tags.get(format!("name:{lang}").as_str()),
+        })
+        .or_else(|| tags.get("name"))
+        .map(smartstring::alias::String::as_str)
+}
+
+/// Convert a relation like `to_feature`, looking up node positions with `position`.
+pub(crate) fn convert(
+    obj: &OsmObj,
+    all_objs: &Objects,
+    position: &impl Fn(NodeId) -> Option<Position>,
+    options: &Options,
+) -> Result<geojson::Feature> {
+    let mut properties = properties(obj, options)?;
+    let key = match options.id_source {
+        IdSource::Relation => None,
+        IdSource::Ars => Some("ars"),
+        IdSource::Name => Some("name"),
+    };
+    let id = Some(match key.and_then(|key| properties.get(key)?.as_str()) {
+        Some(id) => geojson::feature::Id::String(id.to_string()),
+        None => geojson::feature::Id::Number(obj.id().inner_id().into()),
+    });
+    if options.properties_only {
+        return Ok(geojson::Feature {
+            id,
+            properties: Some(properties),
+            ..geojson::Feature::default()
+        });
+    }
+    let name = properties["name"].as_str().unwrap_or_default().to_string();
+
+    let geometry = Geometry::new(match obj {
+        OsmObj::Relation(_) => as_polygon(obj, all_objs, position, options)
+            .with_context(|| format!("cannot convert object '{name}' to polygon"))?,
+        OsmObj::Way(way) => as_line_or_polygon(way, position, options)
+            .with_context(|| format!("cannot convert object '{name}' to line"))?,
+        OsmObj::Node(node) => {
+            let point = Position::from(node);
+            geojson::Value::Point(vec![options.round(*point.0), options.round(*point.1)])
+        }
+    });
+
+    // Relations are only converted to lines if their outer ring is incomplete.
+    if obj.is_relation() && matches!(geometry.value, geojson::Value::MultiLineString(_)) {
+        properties.insert("incomplete".to_string(), true.into());
+    }
+    if !polygons(&geometry.value).is_empty() {
+        properties.insert("area_km2".to_string(), area_km2(&geometry.value).into());
+    }
+    let center = if options.point_on_surface {
+        point_on_surface(&geometry.value)
+    } else {
+        centroid(&geometry.value)
+    };
+    if let Some([lon, lat]) = center {
+        properties.insert(
+            "center".to_string(),
+            json!([options.round(lon), options.round(lat)]),
+        );
+    }
+    let geometry = if options.as_lines {
+        Geometry::new(to_lines(geometry.value))
+    } else {
+        geometry
+    };
+
+    Ok(geojson::Feature {
+        id,
+        bbox: BBox::of(&geometry.value).map(Into::into),
+        geometry: Some(geometry),
+        properties: Some(properties),
+        ..geojson::Feature::default()
+    })
+}
+
+/// The properties of `obj` as described for `to_feature`, apart from those derived from its
+/// geometry.
+fn properties(obj: &OsmObj, options: &Options) -> Result<geojson::JsonObject> {
+    let tags = obj.tags();
+    let name = name_of(tags, &options.name_langs).ok_or_else(|| anyhow!("'name' is missing"))?;
+    let prefixed = tags.get("name:prefix").map(|p| format!("{p} {name}"));
+    let (name, display_name) = if options.merge_name_prefix {
+        (prefixed.unwrap_or_else(|| name.to_string()), None)
+    } else {
+        (name.to_string(), prefixed)
+    };
+    let admin_level = match tags.get("admin_level") {
+        Some(admin_level) => Some(parse_admin_level(
+            admin_level,
+            options.admin_level_fallback,
+        )?),
+        None if obj.is_relation() => bail!("'admin_level' is missing"),
+        None => None,
+    };
+
+    let serde_json::Value::Object(mut properties) = json!({
+        "name": name,
+        "osm_type": osm_type(obj.id()),
+    }) else {
+        todo!()
+    };
+    if let Some(display_name) = display_name {
+        properties.insert("display_name".to_string(), display_name.into());
+    }
+    if let Some(admin_level) = admin_level {
+        properties.insert("adminLevel".to_string(), admin_level);
+    }
+    if let Some(ars) = options
+        .id_tag
+        .as_ref()
+        .and_then(|key| tags.get(key.as_str()))
+    {
+        properties.insert("ars".to_string(), ars.as_str().into());
+    }
+    let excluded = |key: &str| options.exclude_tags.iter().any(|excluded| excluded == key);
+    let value = |key: &str, value: &str| {
+        if options.numeric_tags.iter().any(|numeric| numeric == key) {
+            if let Some(number) = parse_number(value) {
+                return serde_json::Value::Number(number);
+            }
+        }
+        value.into()
+    };
+    for key in options.properties.iter().filter(|key| !excluded(key)) {
+        if let Some(v) = tags.get(key.as_str()) {
+            properties.insert(key.clone(), value(key, v));
+        }
+    }
+    if options.all_tags {
+        for (key, v) in tags.iter().filter(|(key, _)| !excluded(key)) {
+            properties
+                .entry(key.as_str())
+                .or_insert_with(|| value(key, v));
+        }
+    }
+
+    Ok(properties)
+}
+
+/// The number in the tag `value`, as integer if possible. Values which don't parse completely,
+/// like "1000 (2011)", and infinite or NaN values are no numbers.
+fn parse_number(value: &str) -> Option<serde_json::Number> {
+    match value.parse::<i64>() {
+        Ok(integer) => Some(integer.into()),
+        Err(_) => serde_json::Number::from_f64(value.parse().ok()?),
+    }
+}
+
+fn as_polygon(
+    obj: &OsmObj,
+    all_objs: &Objects,
+    position: &impl Fn(NodeId) -> Option<Position>,
+    options: &Options,
+) -> Result<geojson::Value> {
+    let to_coords = |way: &Way| -> Option<Vec<Position>> {
+        way.nodes.iter().map(|node_id| position(*node_id)).collect()
+    };
+
+    let relation = obj
+        .relation()
+        .ok_or_else(|| anyhow!("'relation' is missing"))?;
+
+    let validate = |ring: &Line, kind: &str| -> Result<()> {
+        if self_intersects(ring) {
+            if options.skip_invalid {
+                bail!("{kind} ring self-intersects");
+            }
+            error!("{kind} ring of relation {} self-intersects", relation.id.0);
+        }
+        Ok(())
+    };
+
+    // The member ways with `role`, and the ids and line strings of those with nodes.
+    let linestrings = |role: &str| -> (Vec<&Way>, Vec<WayId>, Vec<Line>) {
+        let members = member_ways(relation, role, all_objs, &mut BTreeSet::new());
+        let (ways, mut linestrings): (Vec<_>, Vec<_>) = members
+            .iter()
+            .filter_map(|way| Some((way.id, Line::try_from(to_coords(way)?).ok()?)))
+            .unzip();
+        snap_endpoints(&mut linestrings, options.snap_tolerance);
+        (members, ways, linestrings)
+    };
+
+    let prepare = |mut ring: Line| {
+        // Repeated nodes in ways leave zero-length segments.
+        ring.dedup();
+        match options.simplify {
+            Some(epsilon) => ring.simplify(epsilon),
+            None => ring,
+        }
+    };
+
+    let (outer_members, outer_ways, outer) = linestrings("outer");
+    // Without outer ways there is nothing to assemble, which is an error in the data model and
+    // not in the topology.
+    if outer_members.is_empty() {
+        bail!("relation {} has no outer ways", relation.id.0);
+    }
+    if outer.is_empty() {
+        bail!("outer ways of relation {} have no nodes", relation.id.0);
+    }
+    // Each group of connected outer ways forms the outer ring of a separate polygon.
+    let outer_rings = connected_rings(&outer, &outer_ways);
+    let outer_rings = match outer_rings.into_iter().collect::<Result<Vec<_>>>() {
+        Ok(rings) => rings,
+        Err(e) if options.include_incomplete => {
+            warn!(
+                "outer ring of relation {} is incomplete: {e}",
+                relation.id.0
+            );
+            return Ok(geojson::Value::MultiLineString(
+                outer
+                    .iter()
+                    .map(|line| coordinates(line, options))
+                    .collect(),
+            ));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut polygons = Vec::new();
+    for ring in outer_rings {
+        let mut ring = prepare(ring);
+        validate(&ring, "outer")?;
+        // Respect the right hand rule: outer rings wind counterclockwise, inner rings clockwise.
+        // Lines have no inside, so their direction doesn't matter.
+        if !options.as_lines {
+            orient(&mut ring, false);
+        }
+        polygons.push(vec![ring]);
+    }
+
+    // Holes are optional, so a broken inner ring only drops the hole and not the feature.
+    let (_, inner_ways, inner) = linestrings("inner");
+    for ring in connected_rings(&inner, &inner_ways) {
+        match ring {
+            Ok(inner) => {
+                let mut inner = prepare(inner);
+                validate(&inner, "inner")?;
+                let Some(polygon) = enclosing(&polygons, &inner) else {
+                    error!(
+                        "skipping inner ring of relation {}: it lies outside of all outer rings",
+                        relation.id.0
+                    );
+                    continue;
+                };
+                if !options.as_lines {
+                    orient(&mut inner, true);
+                }
+                polygons[polygon].push(inner);
+            }
+            Err(e) => error!("skipping inner ring of relation {}: {e}", relation.id.0),
+        }
+    }
+
+    let polygons = polygons
+        .into_iter()
+        .flat_map(|rings| split_antimeridian(&rings).unwrap_or_else(|| vec![rings]))
+        .collect::<Vec<_>>();
+    Ok(match polygons.as_slice() {
+        [] => bail!("relation {} has no outer rings", relation.id.0),
+        [rings] => geojson::Value::Polygon(ring_coordinates(rings, options)),
+        polygons => geojson::Value::MultiPolygon(
+            polygons
+                .iter()
+                .map(|rings| ring_coordinates(rings, options))
+                .collect(),
+        ),
+    })
+}
+
+/// Convert a way to a polygon if it is closed, else to a line string.
+fn as_line_or_polygon(
+    way: &Way,
+    position: &impl Fn(NodeId) -> Option<Position>,
+    options: &Options,
+) -> Result<geojson::Value> {
+    let positions = way
+        .nodes
+        .iter()
+        .map(|node_id| position(*node_id))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| anyhow!("nodes of way {} are missing", way.id.0))?;
+    let mut line = Line::try_from(positions).map_err(|e| anyhow!(e))?;
+
+    line.dedup();
+    if let Some(epsilon) = options.simplify {
+        line = line.simplify(epsilon);
+    }
+
+    Ok(if line.0.len() >= 4 && line.start() == line.end() {
+        if !options.as_lines {
+            orient(&mut line, false);
+        }
+        geojson::Value::Polygon(vec![coordinates(&line, options)])
+    } else {
+        geojson::Value::LineString(coordinates(&line, options))
+    })
+}
+
+/// The positions of `line`, rounded as configured in `options`.
+fn coordinates(line: &Line, options: &Options) -> Vec<Vec<f64>> {
+    line.0
+        .iter()
+        .map(|p| vec![options.round(*p.0), options.round(*p.1)])
+        .collect()
+}
+
+/// The positions of each of `rings`. Rounding only happens here so ring assembly works on the full
+/// precision.
+fn ring_coordinates(rings: &[Line], options: &Options) -> Vec<Vec<Vec<f64>>> {
+    rings
+        .iter()
+        .map(|ring| coordinates(ring, options))
+        .collect()
+}
+
+/// Split a polygon crossing the antimeridian into the parts east and west of it, as recommended
+/// in RFC 7946. Crossing is detected by a jump of more than 180 degrees in longitude between two
+/// points of the outer ring. Returns `None` for polygons which do not cross.
+fn split_antimeridian(rings: &[Line]) -> Option<Vec<Vec<Line>>> {
+    let outer = rings.first()?;
+    if !outer
+        .0
+        .windows(2)
+        .any(|s| (*s[1].0 - *s[0].0).abs() > 180.0)
+    {
+        return None;
+    }
+
+    // Make longitudes continuous, shifted so the rings cross 180 instead of -180.
+    let unwrap = |ring: &Line, reference: f64| -> Line {
+        let mut previous = reference;
+        Line(
+            ring.0
+                .iter()
+                .map(|p| {
+                    let lon = *p.0 + 360.0 * ((previous - *p.0) / 360.0).round();
+                    previous = lon;
+                    Position::new(lon, *p.1)
+                })
+                .collect(),
+        )
+    };
+    let outer = unwrap(outer, 180.0);
+
+    let [west, east] = [true, false].map(|west| {
+        let piece = clip_at_antimeridian(&outer, west)?;
+        let holes = rings[1..]
+            .iter()
+            .filter_map(|ring| clip_at_antimeridian(&unwrap(ring, *outer.start().0), west));
+        Some(std::iter::once(piece).chain(holes).collect::<Vec<_>>())
+    });
+
+    Some(west.into_iter().chain(east).collect())
+}
+
+/// Clip `ring` to the part west (longitude up to 180) or east (from 180) of the antimeridian,
+/// with Sutherland-Hodgman. The east part is moved back by 360 degrees. Returns `None` if less
+/// than a triangle remains.
+fn clip_at_antimeridian(ring: &Line, west: bool) -> Option<Line> {
+    let inside = |p: &Position| if west { *p.0 <= 180.0 } else { *p.0 >= 180.0 };
+    let crossing = |from: &Position, to: &Position| {
+        Position::new(
+            180.0,
+            *from.1 + (*to.1 - *from.1) * (180.0 - *from.0) / (*to.0 - *from.0),
+        )
+    };
+
+    let mut clipped = Vec::new();
+    for segment in ring.0.windows(2) {
+        let (from, to) = (&segment[0], &segment[1]);
+        match (inside(from), inside(to)) {
+            (true, true) => clipped.push(*to),
+            (true, false) => clipped.push(crossing(from, to)),
+            (false, true) => clipped.extend([crossing(from, to), *to]),
+            (false, false) => {}
+        }
+    }
+
+    let offset = if west { 0.0 } else { -360.0 };
+    let mut clipped = Line(
+        clipped
+            .into_iter()
+            .map(|p| Position::new(*p.0 + offset, *p.1))
+            .collect(),
+    );
+    if let Some(&start) = clipped.0.first() {
+        if clipped.end() != &start {
+            clipped.0.push(start);
+        }
+    }
+    clipped.dedup();
+
+    (clipped.0.len() >= 4).then_some(clipped)
+}
+
+/// Collect the member ways of `relation` with `role`. Member relations with the same role are
+/// searched for such ways as well, and so are `subarea` members for outer ways if there are
+/// neither outer ways nor relations. `visited` holds the relations already searched to break
+/// cycles.
+fn member_ways<'a>(
+    relation: &'a Relation,
+    role: &str,
+    all_objs: &'a Objects,
+    visited: &mut BTreeSet<RelationId>,
+) -> Vec<&'a Way> {
+    if !visited.insert(relation.id) {
+        return vec![];
+    }
+
+    let members = |role: &str| {
+        relation
+            .refs
+            .iter()
+            .filter(|child: &&Ref| child.role.as_str() == role)
+            .filter_map(|child| all_objs.get(&child.member))
+            .collect::<Vec<_>>()
+    };
+
+    let mut ways = members(role)
+        .into_iter()
+        .filter_map(OsmObj::way)
+        .collect::<Vec<_>>();
+    let mut children = members(role)
+        .into_iter()
+        .filter_map(OsmObj::relation)
+        .collect::<Vec<_>>();
+    if role == "outer" && ways.is_empty() && children.is_empty() {
+        children.extend(members("subarea").into_iter().filter_map(OsmObj::relation));
+    }
+
+    for child in children {
+        ways.extend(member_ways(child, role, all_objs, visited));
+    }
+
+    ways
+}
+
+/// Move line endpoints lying within `tolerance` degrees of each other onto a common position so
+/// ring assembly can match them exactly. The first endpoint seen becomes the common position.
+fn snap_endpoints(linestrings: &mut [Line], tolerance: f64) {
+    if tolerance <= 0.0 {
+        return;
+    }
+
+    let mut canonical: Vec<Position> = Vec::new();
+    let mut snap = |p: &mut Position| {
+        if let Some(c) = canonical.iter().find(|c| c.distance(p) <= tolerance) {
+            *p = *c;
+        } else {
+            canonical.push(*p);
+        }
+    };
+
+    for linestring in linestrings {
+        if let Some(start) = linestring.0.first_mut() {
+            snap(start);
+        }
+        if let Some(end) = linestring.0.last_mut() {
+            snap(end);
+        }
+    }
+}
+
+/// Assemble the rings of each group of connected `linestrings`, see `group_connected`. `ways` are
+/// the ids of the line strings, for reporting. A group which doesn't form rings is one error.
+fn connected_rings(linestrings: &[Line], ways: &[WayId]) -> Vec<Result<Line>> {
+    group_connected(linestrings)
+        .iter()
+        .flat_map(|group| {
+            let ways = group.iter().map(|&i| ways[i]).collect::<Vec<_>>();
+            let group = group
+                .iter()
+                .map(|&i| linestrings[i].clone())
+                .collect::<Vec<_>>();
+            match create_continuous_linering(&group, &ways) {
+                Ok(rings) => rings.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            }
+        })
+        .collect()
+}
+
+/// Partition line strings into groups connected by shared endpoints, e.g., the parts of
+/// separate rings, and return the indices of each group. The first index of each group is its
+/// lowest.
+fn group_connected(linestrings: &[Line]) -> Vec<Vec<usize>> {
+    let mut by_endpoint = HashMap::<Position, Vec<usize>>::new();
+    for (i, linestring) in linestrings.iter().enumerate() {
+        by_endpoint.entry(*linestring.start()).or_default().push(i);
+        by_endpoint.entry(*linestring.end()).or_default().push(i);
+    }
+
+    let mut visited = vec![false; linestrings.len()];
+    let mut groups = Vec::new();
+
+    for seed in 0..linestrings.len() {
+        if visited[seed] {
+            continue;
+        }
+        visited[seed] = true;
+
+        let mut group = Vec::new();
+        let mut pending = vec![seed];
+        while let Some(i) = pending.pop() {
+            let linestring = &linestrings[i];
+            for endpoint in [linestring.start(), linestring.end()] {
+                for &j in &by_endpoint[endpoint] {
+                    if !visited[j] {
+                        visited[j] = true;
+                        pending.push(j);
+                    }
+                }
+            }
+            group.push(i);
+        }
+
+        groups.push(group);
+    }
+
+    groups
+}
+
+/// Create continuous rings from line strings, which stem from `ways` in the same order. Line
+/// ends have to match exactly, so snap them with `snap_endpoints` first. Rings touching at a node
+/// where ways meet, like two enclaves sharing a corner, are returned separately rather than as one
+/// pinched ring. Errors name the ways which don't fit into a ring.
+fn create_continuous_linering(linestrings: &[Line], ways: &[WayId]) -> Result<Vec<Line>> {
+    if linestrings.is_empty() {
+        bail!("no linestrings")
+    }
+
+    // Each endpoint of a ring is shared by an even number of line ends, so report the first
+    // which is not before assembling anything.
+    let mut ends = HashMap::<Position, usize>::new();
+    for linestring in linestrings {
+        *ends.entry(*linestring.start()).or_default() += 1;
+        *ends.entry(*linestring.end()).or_default() += 1;
+    }
+    for (linestring, way) in linestrings.iter().zip(ways) {
+        for end in [linestring.start(), linestring.end()] {
+            if ends[end] % 2 == 1 {
+                bail!(
+                    "way {} ends at {end:?} without connecting to another way",
+                    way.0
+                );
+            }
+        }
+    }
+
+    // Index every linestring by its endpoints, including the one the ring starts from.
+    let mut endpoints = MultiMap::default();
+    for (i, linestring) in linestrings.iter().enumerate() {
+        endpoints.insert(*linestring.start(), i);
+        endpoints.insert(*linestring.end(), i);
+    }
+
+    let mut rings = Vec::new();
+    let mut used = vec![false; linestrings.len()];
+
+    // Start from the first linestring, and after each closed ring from the first one left.
+    while !endpoints.is_empty() {
+        let first_index = used
+            .iter()
+            .position(|used| !used)
+            .expect("linestring is indexed");
+        // The seed is consumed up front, so it cannot be appended to itself.
+        endpoints.remove(&first_index);
+        used[first_index] = true;
+        let mut continuous_line = linestrings[first_index].clone();
+        // The indices in `continuous_line` where linestrings were joined.
+        let mut joints = vec![0];
+
+        loop {
+            // Returning to an earlier joint closes a ring, which is cut off the line.
+            let last = continuous_line.0.len() - 1;
+            if let Some(&joint) = joints
+                .iter()
+                .find(|&&i| i < last && continuous_line.0[i] == continuous_line.0[last])
+            {
+                let ring = Line(continuous_line.0.split_off(joint));
+                if joint == 0 {
+                    rings.push(ring);
+                    break;
+                }
+                continuous_line.0.push(*ring.start());
+                rings.push(ring);
+                joints.retain(|&i| i < joint);
+                continue;
+            }
+
+            let Some(next_index) = endpoints.consume_one(continuous_line.end()) else {
+                let unused = ways
+                    .iter()
+                    .zip(&used)
+                    .filter(|(_, &used)| !used)
+                    .map(|(way, _)| way.0)
+                    .join(", ");
+                bail!("ways not connected to the ring: {unused}")
+            };
+
+            used[next_index] = true;
+            joints.push(last);
+            continuous_line.extend(&linestrings[next_index])?;
+        }
+    }
+
+    Ok(rings)
+}
+
+/// The index of the smallest of `polygons` whose outer ring contains `ring`, so a hole nested in
+/// several, like in a lake on an island, belongs to the innermost. Containment is tested for the
+/// first position of `ring` which is not also on the outer ring, as holes may touch it.
+fn enclosing(polygons: &[Vec<Line>], ring: &Line) -> Option<usize> {
+    let area = |ring: &Line| {
+        ring.0
+            .windows(2)
+            .map(|s| *s[0].0 * *s[1].1 - *s[1].0 * *s[0].1)
+            .sum::<f64>()
+            .abs()
+    };
+
+    polygons
+        .iter()
+        .enumerate()
+        .filter_map(|(i, rings)| Some((i, rings.first()?)))
+        .filter(|(_, outer)| {
+            let point = ring.0.iter().find(|p| !outer.0.contains(p));
+            ring_contains(&outer.0, point.unwrap_or(ring.start()))
+        })
+        .min_by(|(_, a), (_, b)| area(a).total_cmp(&area(b)))
+        .map(|(i, _)| i)
+}
+
+/// Check whether `p` lies inside the closed `ring` (ray casting).
+fn ring_contains(ring: &[Position], point: &Position) -> bool {
+    let (x, y) = (*point.0, *point.1);
+    let mut inside = false;
+
+    for segment in ring.windows(2) {
+        let (from, to) = (&segment[0], &segment[1]);
+        if (*from.1 > y) != (*to.1 > y)
+            && x < (*to.0 - *from.0) * (y - *from.1) / (*to.1 - *from.1) + *from.0
+        {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
+
+/// Check whether two line segments share at least one point.
+fn segments_intersect(a: (&Position, &Position), b: (&Position, &Position)) -> bool {
+    // Sign of the cross product of `q - p` and `r - p`.
+    let orientation = |p: &Position, q: &Position, r: &Position| {
+        ((*q.0 - *p.0) * (*r.1 - *p.1) - (*q.1 - *p.1) * (*r.0 - *p.0)).signum()
+    };
+    let on_segment = |p: &Position, q: &Position, r: &Position| {
+        r.0 >= p.0.min(q.0) && r.0 <= p.0.max(q.0) && r.1 >= p.1.min(q.1) && r.1 <= p.1.max(q.1)
+    };
+
+    let o1 = orientation(a.0, a.1, b.0);
+    let o2 = orientation(a.0, a.1, b.1);
+    let o3 = orientation(b.0, b.1, a.0);
+    let o4 = orientation(b.0, b.1, a.1);
+
+    if o1 * o2 < 0.0 && o3 * o4 < 0.0 {
+        return true;
+    }
+
+    // Collinear or touching cases.
+    (o1 == 0.0 && on_segment(a.0, a.1, b.0))
+        || (o2 == 0.0 && on_segment(a.0, a.1, b.1))
+        || (o3 == 0.0 && on_segment(b.0, b.1, a.0))
+        || (o4 == 0.0 && on_segment(b.0, b.1, a.1))
+}
+
+/// Check whether any two non-adjacent segments of `ring` intersect or touch, as in a figure
+/// eight. Only segments overlapping in longitude are compared.
+fn self_intersects(ring: &Line) -> bool {
+    let mut points = ring.0.clone();
+    points.dedup();
+
+    let segments = points.windows(2).collect::<Vec<_>>();
+    let n = segments.len();
+    let min_x = |segment: &[Position]| segment[0].0.min(segment[1].0);
+    let max_x = |segment: &[Position]| segment[0].0.max(segment[1].0);
+
+    let mut order = (0..n).collect::<Vec<_>>();
+    order.sort_by_key(|&i| min_x(segments[i]));
+
+    for (k, &i) in order.iter().enumerate() {
+        let candidates = order[k + 1..]
+            .iter()
+            .take_while(|&&j| min_x(segments[j]) <= max_x(segments[i]));
+
+        for &j in candidates {
+            // Adjacent segments, including the last and first of a closed ring, share a point.
+            let distance = i.abs_diff(j);
+            if distance == 1 || (distance == n - 1 && points.first() == points.last()) {
+                continue;
+            }
+
+            if segments_intersect(
+                (&segments[i][0], &segments[i][1]),
+                (&segments[j][0], &segments[j][1]),
+            ) {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+/// Reverse `ring` if necessary so that it winds `clockwise` or counterclockwise.
+fn orient(ring: &mut Line, clockwise: bool) {
+    if is_clockwise(ring) != clockwise {
+        ring.0.reverse();
+    }
+}
+
+/// Calculate the orientation of the ring
+fn is_clockwise(ring: &Line) -> bool {
+    // Calculate the signed area under the curve (Shoelace formula). Steps in longitude are taken
+    // the short way round, so rings crossing the antimeridian are unwrapped instead of spanning
+    // the globe in the opposite direction.
+    let step = |from: f64, to: f64| {
+        let d = to - from;
+        if d > 180.0 {
+            d - 360.0
+        } else if d < -180.0 {
+            d + 360.0
+        } else {
+            d
+        }
+    };
+
+    let cur = ring.0.iter();
+    let next = ring.0.iter().chain(ring.0.iter()).skip(1);
+    cur.zip(next)
+        .map(|(c, n)| step(*c.0, *n.0) * *(n.1 + c.1))
+        .sum::<f64>()
+        > 0.0
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{Line, Position};
+
+    #[test]
+    fn extend() {
+        let p1 = Position::new(0., 0.);
+        let p2 = Position::new(1., 0.);
+
+        {
+            let mut l = Line::try_from(vec![p1, p2]).unwrap();
+            l.extend(&Line::try_from(vec![p2, p1]).unwrap()).unwrap();
+            assert_eq!(l, Line::try_from(vec![p1, p2, p1]).unwrap());
+        }
+
+        {
+            let mut l = Line::try_from(vec![p1, p2]).unwrap();
+            l.extend(&Line::try_from(vec![p1, p2]).unwrap()).unwrap();
+            assert_eq!(l, Line::try_from(vec![p1, p2, p1]).unwrap());
+        }
+
+        let p3 = Position::new(2., 0.);
+
+        {
+            let mut l = Line::try_from(vec![p1, p2]).unwrap();
+            l.extend(&Line::try_from(vec![p1, p3]).unwrap()).unwrap();
+            assert_eq!(l, Line::try_from(vec![p2, p1, p3]).unwrap());
+        }
+
+        {
+            let mut l = Line::try_from(vec![p1, p2]).unwrap();
+            l.extend(&Line::try_from(vec![p3, p1]).unwrap()).unwrap();
+            assert_eq!(l, Line::try_from(vec![p2, p1, p3]).unwrap());
+        }
+
+        {
+            let mut l = Line::try_from(vec![p1, p2]).unwrap();
+            assert!(l.extend(&Line::try_from(vec![p3, p3]).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn is_clockwise() {
+        use super::is_clockwise;
+
+        // Points in the four quadrants of a cartesian coordinate system. Numbering here is
+        // counterclockwise.
+        let q1 = Position::new(1.0, 1.0);
+        let q2 = Position::new(1.0, -1.0);
+        let q3 = Position::new(-1.0, -1.0);
+        let q4 = Position::new(-1.0, 1.0);
+
+        // Segments with two elements are both clock- and counterclockwise.
+        assert!(!is_clockwise(&Line::try_from(vec![q1, q2]).unwrap()));
+        assert!(!is_clockwise(&Line::try_from(vec![q2, q1]).unwrap()));
+
+        assert!(is_clockwise(&Line::try_from(vec![q1, q2, q3, q4]).unwrap()));
+        assert!(!is_clockwise(
+            &Line::try_from(vec![q4, q3, q2, q1]).unwrap()
+        ));
+    }
+
+    #[test]
+    fn is_clockwise_antimeridian() {
+        use super::is_clockwise;
+
+        // A ring straddling ±180°, walked east across the antimeridian, north and back west.
+        let ring = vec![
+            Position::new(179.0, 0.0),
+            Position::new(-179.0, 0.0),
+            Position::new(-179.0, 1.0),
+            Position::new(179.0, 1.0),
+            Position::new(179.0, 0.0),
+        ];
+        assert!(!is_clockwise(&Line::try_from(ring.clone()).unwrap()));
+
+        let reversed = ring.into_iter().rev().collect::<Vec<_>>();
+        assert!(is_clockwise(&Line::try_from(reversed).unwrap()));
+    }
+
+    #[test]
+    fn dedup() {
+        let p1 = Position::new(0., 0.);
+        let p2 = Position::new(1., 0.);
+        let p3 = Position::new(1., 1.);
+
+        let mut l = Line::try_from(vec![p1, p1, p2, p3, p3, p3, p1]).unwrap();
+        l.dedup();
+        assert_eq!(l, Line::try_from(vec![p1, p2, p3, p1]).unwrap());
+
+        let mut l = Line::try_from(vec![p1, p1, p1]).unwrap();
+        l.dedup();
+        assert_eq!(l, Line::try_from(vec![p1, p1]).unwrap());
+    }
+
+    #[test]
+    fn simplify() {
+        let p1 = Position::new(0., 0.);
+        let p2 = Position::new(1., 0.01);
+        let p3 = Position::new(2., 0.);
+        let p4 = Position::new(2., 2.);
+        let p5 = Position::new(0., 2.);
+
+        let line = Line::try_from(vec![p1, p2, p3]).unwrap();
+        assert_eq!(line.simplify(0.001), line);
+        assert_eq!(line.simplify(0.1), Line::try_from(vec![p1, p3]).unwrap());
+
+        let ring = Line::try_from(vec![p1, p2, p3, p4, p5, p1]).unwrap();
+        assert_eq!(
+            ring.simplify(0.1),
+            Line::try_from(vec![p1, p3, p4, p5, p1]).unwrap()
+        );
+
+        // Rings keep at least four points.
+        assert_eq!(ring.simplify(10.), ring);
+    }
+
+    #[test]
+    fn self_intersects() {
+        use super::self_intersects;
+
+        let p1 = Position::new(0., 0.);
+        let p2 = Position::new(1., 0.);
+        let p3 = Position::new(1., 1.);
+        let p4 = Position::new(0., 1.);
+
+        assert!(!self_intersects(
+            &Line::try_from(vec![p1, p2, p3, p4, p1]).unwrap()
+        ));
+
+        // Duplicate points don't count as touching.
+        assert!(!self_intersects(
+            &Line::try_from(vec![p1, p2, p2, p3, p4, p1]).unwrap()
+        ));
+
+        // Joining the ways in the wrong order yields a figure eight.
+        assert!(self_intersects(
+            &Line::try_from(vec![p1, p2, p4, p3, p1]).unwrap()
+        ));
+
+        // Touching in a single vertex.
+        let p5 = Position::new(2., 0.);
+        let p6 = Position::new(2., 1.);
+        assert!(self_intersects(
+            &Line::try_from(vec![p1, p2, p3, p4, p1, p5, p6, p1]).unwrap()
+        ));
+    }
+
+    #[test]
+    fn create_continuous_linering_reproducible() {
+        use super::create_continuous_linering;
+        use osmpbfreader::WayId;
+
+        // Two loops touching at the origin, so the ring can continue with either at that point.
+        let lines = [
+            vec![(0., 0.), (1., 0.)],
+            vec![(1., 0.), (1., 1.), (0., 0.)],
+            vec![(0., 0.), (-1., 0.)],
+            vec![(-1., 0.), (-1., -1.), (0., 0.)],
+        ]
+        .map(|ps| {
+            Line::try_from(
+                ps.into_iter()
+                    .map(|(x, y)| Position::new(x, y))
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap()
+        });
+        let ways = (1..=4).map(WayId).collect::<Vec<_>>();
+
+        // Every run hashes endpoints with a different random seed.
+        let first = create_continuous_linering(&lines, &ways).unwrap();
+        for _ in 0..20 {
+            assert_eq!(create_continuous_linering(&lines, &ways).unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn create_continuous_linering() {
+        use super::create_continuous_linering;
+        use osmpbfreader::WayId;
+
+        // Assemble line strings from ways 1, 2, and so on.
+        let ring = |lines: &[Line]| {
+            let ways = (1..).map(WayId).take(lines.len()).collect::<Vec<_>>();
+            create_continuous_linering(lines, &ways)
+        };
+
+        let p1 = Position::new(0., 0.);
+        let p2 = Position::new(1., 0.);
+        let p3 = Position::new(2., 0.);
+
+        {
+            let l = Line::try_from(vec![p1, p1]).unwrap();
+            assert_eq!(ring(std::slice::from_ref(&l)).unwrap(), [l]);
+        }
+
+        {
+            let l = Line::try_from(vec![p1, p2, p1]).unwrap();
+            assert_eq!(ring(std::slice::from_ref(&l)).unwrap(), [l]);
+        }
+
+        {
+            let l = Line::try_from(vec![p1, p2, p3, p1]).unwrap();
+            assert_eq!(ring(std::slice::from_ref(&l)).unwrap(), [l]);
+        }
+
+        {
+            let l1 = Line::try_from(vec![p1, p2]).unwrap();
+            let l2 = Line::try_from(vec![p2, p1]).unwrap();
+            let l3 = Line::try_from(vec![p1, p2, p1]).unwrap();
+            assert_eq!(ring(&[l1, l2]).unwrap(), [l3]);
+        }
+
+        {
+            let l1 = Line::try_from(vec![p1, p2]).unwrap();
+            let l2 = Line::try_from(vec![p1, p2]).unwrap();
+            let l3 = Line::try_from(vec![p1, p2, p1]).unwrap();
+            assert_eq!(ring(&[l1, l2]).unwrap(), [l3]);
+        }
+
+        {
+            // The first linestring runs against the others.
+            let l1 = Line::try_from(vec![p2, p1]).unwrap();
+            let l2 = Line::try_from(vec![p2, p3]).unwrap();
+            let l3 = Line::try_from(vec![p3, p1]).unwrap();
+            assert_eq!(
+                ring(&[l1, l2, l3]).unwrap(),
+                [Line::try_from(vec![p2, p1, p3, p2]).unwrap()]
+            );
+        }
+
+        {
+            // A linestring may only be used once.
+            let l1 = Line::try_from(vec![p1, p2]).unwrap();
+            let l2 = Line::try_from(vec![p2, p3]).unwrap();
+            assert!(ring(&[l1.clone(), l2, l1]).is_err());
+        }
+
+        {
+            // Errors name the ways which don't fit.
+            let l1 = Line::try_from(vec![p1, p2]).unwrap();
+            let l2 = Line::try_from(vec![p2, p3]).unwrap();
+            assert_eq!(
+                ring(&[l1, l2]).unwrap_err().to_string(),
+                "way 1 ends at [0.0, 0.0] without connecting to another way"
+            );
+        }
+
+        {
+            // Separate rings are assembled one after the other.
+            let l1 = Line::try_from(vec![p1, p2, p1]).unwrap();
+            let l2 = Line::try_from(vec![p3, Position::new(2., 1.), p3]).unwrap();
+            assert_eq!(ring(&[l1.clone(), l2.clone()]).unwrap(), [l1, l2]);
+        }
+
+        {
+            // Rings touching at a node where ways meet stay separate rings, whichever way the
+            // assembly takes at that node.
+            let p4 = Position::new(1., 1.);
+            let p5 = Position::new(2., 1.);
+            let l1 = Line::try_from(vec![p1, p2]).unwrap();
+            let l2 = Line::try_from(vec![p2, p3]).unwrap();
+            let l3 = Line::try_from(vec![p3, p5, p2]).unwrap();
+            let l4 = Line::try_from(vec![p2, p4, p1]).unwrap();
+            assert_eq!(
+                ring(&[l1, l2, l3, l4]).unwrap(),
+                [
+                    Line::try_from(vec![p2, p3, p5, p2]).unwrap(),
+                    Line::try_from(vec![p1, p2, p4, p1]).unwrap(),
+                ]
+            );
+        }
+
+        {
+            // The last node is off by a rounding error, which snapping absorbs.
+            let p1_ = Position::new(0.000_000_1, 0.);
+            let mut lines = [Line::try_from(vec![p1, p2, p3, p1_]).unwrap()];
+            let ways = [WayId(1)];
+            assert!(create_continuous_linering(&lines, &ways).is_err());
+            super::snap_endpoints(&mut lines, 0.000_001);
+            assert_eq!(
+                create_continuous_linering(&lines, &ways).unwrap(),
+                [Line::try_from(vec![p1, p2, p3, p1]).unwrap()]
+            );
+        }
+    }
+
+    #[test]
+    fn snap_endpoints() {
+        use super::snap_endpoints;
+
+        let p1 = Position::new(0., 0.);
+        let p2 = Position::new(1., 0.);
+        let p2_ = Position::new(1., 0.000_000_1);
+        let p1_ = Position::new(0.000_000_1, 0.);
+
+        let mut lines = vec![
+            Line::try_from(vec![p1, p2]).unwrap(),
+            Line::try_from(vec![p2_, p1_]).unwrap(),
+        ];
+
+        snap_endpoints(&mut lines, 0.0);
+        assert_eq!(lines[1], Line::try_from(vec![p2_, p1_]).unwrap());
+
+        snap_endpoints(&mut lines, 0.000_001);
+        assert_eq!(lines[1], Line::try_from(vec![p2, p1]).unwrap());
+    }
+
+    #[test]
+    fn group_connected() {
+        use super::group_connected;
+
+        let p1 = Position::new(0., 0.);
+        let p2 = Position::new(1., 0.);
+        let p3 = Position::new(5., 5.);
+        let p4 = Position::new(6., 5.);
+
+        let a1 = Line::try_from(vec![p1, p2]).unwrap();
+        let b = Line::try_from(vec![p3, p4, p3]).unwrap();
+        let a2 = Line::try_from(vec![p1, p2]).unwrap();
+
+        let groups = group_connected(&[a1, b, a2]);
+        assert_eq!(groups, vec![vec![0, 2], vec![1]]);
+    }
+
+    /// Add a way with nodes at `coords` to `objs`.
+    fn add_way(objs: &mut crate::util::Objects, id: i64, coords: &[(i32, i32)]) {
+        use osmpbfreader::{Node, NodeId, OsmId, OsmObj, Tags, Way, WayId};
+
+        let nodes = (id * 100..)
+            .zip(coords)
+            .map(|(node_id, &(lon, lat))| {
+                let node_id = NodeId(node_id);
+                objs.insert(
+                    OsmId::Node(node_id),
+                    OsmObj::Node(Node {
+                        id: node_id,
+                        tags: Tags::new(),
+                        decimicro_lat: lat * 10_000_000,
+                        decimicro_lon: lon * 10_000_000,
+                    }),
+                );
+                node_id
+            })
+            .collect();
+        objs.insert(
+            OsmId::Way(WayId(id)),
+            OsmObj::Way(Way {
+                id: WayId(id),
+                tags: Tags::new(),
+                nodes,
+            }),
+        );
+    }
+
+    /// Insert `tags` into the tags of `obj`, whatever kind of object it is.
+    pub(crate) fn add_tags(obj: &mut osmpbfreader::OsmObj, tags: &[(&str, &str)]) {
+        use osmpbfreader::OsmObj;
+
+        let obj_tags = match obj {
+            OsmObj::Node(node) => &mut node.tags,
+            OsmObj::Way(way) => &mut way.tags,
+            OsmObj::Relation(relation) => &mut relation.tags,
+        };
+        for &(key, value) in tags {
+            obj_tags.insert(key.into(), value.into());
+        }
+    }
+
+    /// Add a relation with `members` to `objs` and return it.
+    pub(crate) fn add_relation(
+        objs: &mut crate::util::Objects,
+        id: i64,
+        members: &[(osmpbfreader::OsmId, &str)],
+    ) -> osmpbfreader::OsmObj {
+        use osmpbfreader::{OsmId, OsmObj, Ref, Relation, RelationId, Tags};
+
+        let relation = OsmObj::Relation(Relation {
+            id: RelationId(id),
+            tags: Tags::new(),
+            refs: members
+                .iter()
+                .map(|&(member, role)| Ref {
+                    member,
+                    role: role.into(),
+                })
+                .collect(),
+        });
+        objs.insert(OsmId::Relation(RelationId(id)), relation.clone());
+        relation
+    }
+
+    #[test]
+    fn as_polygon_with_holes() {
+        use super::{as_polygon, is_clockwise, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, WayId};
+
+        let mut objs = Objects::default();
+
+        // Outer ring split over two ways, two holes, and a hole which is not closed.
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10)]);
+        add_way(&mut objs, 2, &[(10, 10), (0, 10), (0, 0)]);
+        add_way(&mut objs, 3, &[(1, 1), (2, 1), (2, 2), (1, 1)]);
+        add_way(&mut objs, 4, &[(5, 5), (5, 6), (6, 6), (5, 5)]);
+        add_way(&mut objs, 5, &[(7, 7), (8, 7), (8, 8)]);
+
+        let relation = add_relation(
+            &mut objs,
+            1,
+            &[
+                (OsmId::Way(WayId(1)), "outer"),
+                (OsmId::Way(WayId(3)), "inner"),
+                (OsmId::Way(WayId(2)), "outer"),
+                (OsmId::Way(WayId(4)), "inner"),
+                (OsmId::Way(WayId(5)), "inner"),
+            ],
+        );
+
+        let geojson::Value::Polygon(rings) =
+            as_polygon(&relation, &objs, &node_in(&objs), &Options::default()).unwrap()
+        else {
+            panic!("expected a polygon");
+        };
+        let rings: Vec<_> = rings
+            .iter()
+            .map(|ring| Line(ring.iter().map(|p| Position::new(p[0], p[1])).collect()))
+            .collect();
+
+        assert_eq!(rings.len(), 3, "{rings:?}");
+        assert_eq!(rings[0].0.len(), 5);
+        assert!(rings[0].0.contains(&Position::new(10., 10.)));
+        assert!(!is_clockwise(&rings[0]));
+        assert!(rings[1].0.contains(&Position::new(2., 2.)));
+        assert!(is_clockwise(&rings[1]));
+        assert!(rings[2].0.contains(&Position::new(6., 6.)));
+        assert!(is_clockwise(&rings[2]));
+    }
+
+    #[test]
+    fn as_polygon_holes_sharing_a_node() {
+        use super::{as_polygon, is_clockwise, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, WayId};
+
+        let mut objs = Objects::default();
+        // Two holes touching at (5, 5), like adjacent enclaves, each split there into two ways.
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)]);
+        add_way(&mut objs, 2, &[(2, 2), (5, 5)]);
+        add_way(&mut objs, 3, &[(5, 5), (8, 5)]);
+        add_way(&mut objs, 4, &[(8, 5), (8, 8), (5, 5)]);
+        add_way(&mut objs, 5, &[(5, 5), (5, 2), (2, 2)]);
+        let relation = add_relation(
+            &mut objs,
+            1,
+            &[
+                (OsmId::Way(WayId(1)), "outer"),
+                (OsmId::Way(WayId(2)), "inner"),
+                (OsmId::Way(WayId(3)), "inner"),
+                (OsmId::Way(WayId(4)), "inner"),
+                (OsmId::Way(WayId(5)), "inner"),
+            ],
+        );
+        let options = Options {
+            skip_invalid: true,
+            ..Options::default()
+        };
+
+        let geojson::Value::Polygon(rings) =
+            as_polygon(&relation, &objs, &node_in(&objs), &options).unwrap()
+        else {
+            panic!("expected a polygon");
+        };
+        assert_eq!(rings.len(), 3, "{rings:?}");
+        for hole in &rings[1..] {
+            assert_eq!(hole.len(), 4);
+            let hole = Line(hole.iter().map(|p| Position::new(p[0], p[1])).collect());
+            assert!(is_clockwise(&hole));
+        }
+    }
+
+    #[test]
+    fn as_polygon_multiple_outer_rings() {
+        use super::{as_polygon, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, WayId};
+
+        let mut objs = Objects::default();
+        // Two squares side by side with a hole each, listed in the opposite order.
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)]);
+        add_way(
+            &mut objs,
+            2,
+            &[(20, 0), (30, 0), (30, 10), (20, 10), (20, 0)],
+        );
+        add_way(&mut objs, 3, &[(22, 2), (24, 2), (24, 4), (22, 2)]);
+        add_way(&mut objs, 4, &[(2, 2), (4, 2), (4, 4), (2, 2)]);
+        let relation = add_relation(
+            &mut objs,
+            1,
+            &[
+                (OsmId::Way(WayId(1)), "outer"),
+                (OsmId::Way(WayId(2)), "outer"),
+                (OsmId::Way(WayId(3)), "inner"),
+                (OsmId::Way(WayId(4)), "inner"),
+            ],
+        );
+
+        let geojson::Value::MultiPolygon(polygons) =
+            as_polygon(&relation, &objs, &node_in(&objs), &Options::default()).unwrap()
+        else {
+            panic!("expected a multi polygon");
+        };
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(polygons[0].len(), 2);
+        assert!(polygons[0][0].contains(&vec![10., 10.]));
+        assert!(polygons[0][1].contains(&vec![4., 4.]));
+        assert_eq!(polygons[1].len(), 2);
+        assert!(polygons[1][0].contains(&vec![30., 10.]));
+        assert!(polygons[1][1].contains(&vec![24., 4.]));
+    }
+
+    #[test]
+    fn as_polygon_outer_rings_sharing_a_node() {
+        use super::{as_polygon, node_in, Options};
+        use crate::util::Objects;
+        use itertools::Itertools;
+        use osmpbfreader::{OsmId, WayId};
+
+        let mut objs = Objects::default();
+        // Two triangles touching at (1, 0), each split there into two ways.
+        add_way(&mut objs, 1, &[(0, 0), (1, 0)]);
+        add_way(&mut objs, 2, &[(1, 0), (0, 1), (0, 0)]);
+        add_way(&mut objs, 3, &[(1, 0), (2, 0)]);
+        add_way(&mut objs, 4, &[(2, 0), (2, 1), (1, 0)]);
+
+        for ways in (1..=4).permutations(4) {
+            let members = ways
+                .iter()
+                .map(|&id| (OsmId::Way(WayId(id)), "outer"))
+                .collect::<Vec<_>>();
+            let relation = add_relation(&mut objs, 1, &members);
+            let options = Options {
+                skip_invalid: true,
+                ..Options::default()
+            };
+            let geojson::Value::MultiPolygon(polygons) =
+                as_polygon(&relation, &objs, &node_in(&objs), &options).unwrap()
+            else {
+                panic!("expected a multi polygon for ways {ways:?}");
+            };
+            assert_eq!(polygons.len(), 2, "{ways:?}");
+            assert!(polygons
+                .iter()
+                .all(|rings| rings.len() == 1 && rings[0].len() == 4));
+        }
+    }
+
+    #[test]
+    fn as_polygon_nested_rings() {
+        use super::{as_polygon, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, WayId};
+
+        let mut objs = Objects::default();
+        // An island in a lake with a pond on it, and a hole touching the outer ring.
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)]);
+        add_way(&mut objs, 2, &[(2, 2), (8, 2), (8, 8), (2, 8), (2, 2)]);
+        add_way(&mut objs, 3, &[(3, 3), (7, 3), (7, 7), (3, 7), (3, 3)]);
+        add_way(&mut objs, 4, &[(4, 4), (5, 4), (5, 5), (4, 4)]);
+        add_way(&mut objs, 5, &[(0, 0), (1, 0), (1, 1), (0, 0)]);
+        let relation = add_relation(
+            &mut objs,
+            1,
+            &[
+                (OsmId::Way(WayId(1)), "outer"),
+                (OsmId::Way(WayId(2)), "inner"),
+                (OsmId::Way(WayId(3)), "outer"),
+                (OsmId::Way(WayId(4)), "inner"),
+                (OsmId::Way(WayId(5)), "inner"),
+            ],
+        );
+
+        let geojson::Value::MultiPolygon(polygons) =
+            as_polygon(&relation, &objs, &node_in(&objs), &Options::default()).unwrap()
+        else {
+            panic!("expected a multi polygon");
+        };
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(polygons[0].len(), 3);
+        assert!(polygons[0][1].contains(&vec![8., 8.]));
+        assert!(polygons[0][2].contains(&vec![1., 1.]));
+        assert_eq!(polygons[1].len(), 2);
+        assert!(polygons[1][1].contains(&vec![5., 5.]));
+    }
+
+    #[test]
+    fn as_polygon_incomplete() {
+        use super::{as_polygon, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, WayId};
+
+        let mut objs = Objects::default();
+
+        // The outer ring misses its closing way.
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10)]);
+        add_way(&mut objs, 2, &[(10, 10), (0, 10)]);
+
+        let relation = add_relation(
+            &mut objs,
+            1,
+            &[
+                (OsmId::Way(WayId(1)), "outer"),
+                (OsmId::Way(WayId(2)), "outer"),
+            ],
+        );
+
+        assert!(as_polygon(&relation, &objs, &node_in(&objs), &Options::default()).is_err());
+
+        let options = Options {
+            include_incomplete: true,
+            ..Options::default()
+        };
+        let geojson::Value::MultiLineString(lines) =
+            as_polygon(&relation, &objs, &node_in(&objs), &options).unwrap()
+        else {
+            panic!("expected a multi line string");
+        };
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], vec![vec![10., 10.], vec![0., 10.]]);
+    }
+
+    #[test]
+    fn write_incomplete_bbox() {
+        use super::{write, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, OsmObj, RelationId, WayId};
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10)]);
+        add_way(&mut objs, 2, &[(10, 10), (0, 10)]);
+        add_relation(
+            &mut objs,
+            1,
+            &[
+                (OsmId::Way(WayId(1)), "outer"),
+                (OsmId::Way(WayId(2)), "outer"),
+            ],
+        );
+        add_tags(
+            objs.get_mut(&OsmId::Relation(RelationId(1))).unwrap(),
+            &[("name", "Musterstadt"), ("admin_level", "8")],
+        );
+
+        let mut out = vec![];
+        let written = |bbox: &str, out: &mut Vec<u8>| {
+            let options = Options {
+                include_incomplete: true,
+                bbox: Some(bbox.parse().unwrap()),
+                ..Options::default()
+            };
+            write(&objs, OsmObj::is_relation, out, &options)
+                .unwrap()
+                .written
+        };
+        assert_eq!(written("5,9,6,11", &mut out), 1);
+        assert!(String::from_utf8(out).unwrap().contains("MultiLineString"));
+        assert_eq!(written("20,20,30,30", &mut vec![]), 0);
+    }
+
+    #[test]
+    fn as_polygon_without_outer_ways() {
+        use super::{as_polygon, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, WayId};
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10), (0, 0)]);
+        let relation = add_relation(&mut objs, 7, &[(OsmId::Way(WayId(1)), "inner")]);
+
+        let options = Options {
+            include_incomplete: true,
+            ..Options::default()
+        };
+        let error = as_polygon(&relation, &objs, &node_in(&objs), &options).unwrap_err();
+        assert_eq!(error.to_string(), "relation 7 has no outer ways");
+    }
+
+    #[test]
+    fn as_polygon_without_nodes() {
+        use super::{as_polygon, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{NodeId, OsmId, WayId};
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10), (0, 0)]);
+        for node_id in 100..104 {
+            objs.remove(&OsmId::Node(NodeId(node_id)));
+        }
+        let relation = add_relation(&mut objs, 7, &[(OsmId::Way(WayId(1)), "outer")]);
+
+        let options = Options {
+            include_incomplete: true,
+            ..Options::default()
+        };
+        let error = as_polygon(&relation, &objs, &node_in(&objs), &options).unwrap_err();
+        assert_eq!(error.to_string(), "outer ways of relation 7 have no nodes");
+    }
+
+    #[test]
+    fn as_polygon_snap_tolerance() {
+        use super::{as_polygon, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, WayId};
+
+        let mut objs = Objects::default();
+        // The ring ends a degree off its start.
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10), (1, 0)]);
+        let relation = add_relation(&mut objs, 1, &[(OsmId::Way(WayId(1)), "outer")]);
+
+        assert!(as_polygon(&relation, &objs, &node_in(&objs), &Options::default()).is_err());
+        let options = Options {
+            snap_tolerance: 1.5,
+            ..Options::default()
+        };
+        assert_eq!(
+            as_polygon(&relation, &objs, &node_in(&objs), &options).unwrap(),
+            geojson::Value::Polygon(vec![vec![
+                vec![0., 0.],
+                vec![10., 0.],
+                vec![10., 10.],
+                vec![0., 0.],
+            ]])
+        );
+    }
+
+    #[test]
+    fn as_polygon_winding() {
+        use super::{as_polygon, is_clockwise, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, WayId};
+
+        let mut objs = Objects::default();
+        // Outer ring clockwise and hole counterclockwise, both the wrong way around.
+        add_way(&mut objs, 1, &[(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)]);
+        add_way(&mut objs, 2, &[(2, 2), (4, 2), (4, 4), (2, 4), (2, 2)]);
+        let relation = add_relation(
+            &mut objs,
+            1,
+            &[
+                (OsmId::Way(WayId(1)), "outer"),
+                (OsmId::Way(WayId(2)), "inner"),
+            ],
+        );
+
+        let geojson::Value::Polygon(rings) =
+            as_polygon(&relation, &objs, &node_in(&objs), &Options::default()).unwrap()
+        else {
+            panic!("expected a polygon");
+        };
+        let rings: Vec<_> = rings
+            .iter()
+            .map(|ring| Line(ring.iter().map(|p| Position::new(p[0], p[1])).collect()))
+            .collect();
+
+        assert!(!is_clockwise(&rings[0]));
+        assert!(is_clockwise(&rings[1]));
+    }
+
+    #[test]
+    fn as_polygon_with_subrelations() {
+        use super::{as_polygon, node_in, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, RelationId, WayId};
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0), (10, 0), (10, 10)]);
+        add_way(&mut objs, 2, &[(10, 10), (0, 10), (0, 0)]);
+        add_way(&mut objs, 3, &[(20, 0), (30, 0), (30, 10), (20, 0)]);
+
+        // Half of the ring is delegated to a child relation, which refers back to its parent.
+        add_relation(
+            &mut objs,
+            2,
+            &[
+                (OsmId::Way(WayId(2)), "outer"),
+                (OsmId::Relation(RelationId(1)), "outer"),
+            ],
+        );
+        let relation = add_relation(
+            &mut objs,
+            1,
+            &[
+                (OsmId::Way(WayId(1)), "outer"),
+                (OsmId::Relation(RelationId(2)), "outer"),
+            ],
+        );
+        let geojson::Value::Polygon(rings) =
+            as_polygon(&relation, &objs, &node_in(&objs), &Options::default()).unwrap()
+        else {
+            panic!("expected a polygon");
+        };
+        assert_eq!(rings[0].len(), 5);
+
+        // Subareas only count without outer ways.
+        let relation = add_relation(
+            &mut objs,
+            3,
+            &[
+                (OsmId::Relation(RelationId(1)), "subarea"),
+                (OsmId::Way(WayId(3)), "outer"),
+            ],
+        );
+        let geojson::Value::Polygon(rings) =
+            as_polygon(&relation, &objs, &node_in(&objs), &Options::default()).unwrap()
+        else {
+            panic!("expected a polygon");
+        };
+        assert_eq!(rings[0].len(), 4);
+
+        let relation = add_relation(&mut objs, 4, &[(OsmId::Relation(RelationId(1)), "subarea")]);
+        assert!(as_polygon(&relation, &objs, &node_in(&objs), &Options::default()).is_ok());
+    }
+
+    #[test]
+    fn to_feature_properties_only() {
+        use super::{to_feature, Options};
+        use crate::util::Objects;
+
+        // Without members there is no polygon, but the properties don't need one.
+        let mut objs = Objects::default();
+        let mut relation = add_relation(&mut objs, 1, &[]);
+        add_tags(
+            &mut relation,
+            &[("name", "Musterstadt"), ("admin_level", "8")],
+        );
+        assert!(to_feature(&relation, &objs, &Options::default()).is_err());
+
+        let options = Options {
+            properties_only: true,
+            ..Options::default()
+        };
+        let feature = to_feature(&relation, &objs, &options).unwrap();
+        assert!(feature.geometry.is_none());
+        assert_eq!(feature.property("name"), Some(&"Musterstadt".into()));
+        assert_eq!(feature.property("adminLevel"), Some(&8.into()));
+        assert!(!feature.contains_property("area_km2"));
+    }
+
+    #[test]
+    fn to_feature_as_lines() {
+        use super::{to_feature, Options};
+        use crate::util::Objects;
+        use geojson::Value;
+        use osmpbfreader::{OsmId, OsmObj, WayId};
+
+        let mut objs = Objects::default();
+        // Both rings wind the wrong way, which lines keep.
+        add_way(&mut objs, 1, &[(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)]);
+        add_way(&mut objs, 2, &[(2, 2), (4, 2), (4, 4), (2, 4), (2, 2)]);
+        let mut relation = add_relation(&mut objs, 1, &[(OsmId::Way(WayId(1)), "outer")]);
+        add_tags(
+            &mut relation,
+            &[("name", "Musterstadt"), ("admin_level", "8")],
+        );
+        let options = Options {
+            as_lines: true,
+            ..Options::default()
+        };
+
+        let feature = to_feature(&relation, &objs, &options).unwrap();
+        assert_eq!(
+            feature.geometry.as_ref().unwrap().value,
+            Value::LineString(vec![
+                vec![0., 0.],
+                vec![0., 10.],
+                vec![10., 10.],
+                vec![10., 0.],
+                vec![0., 0.],
+            ])
+        );
+        assert!(feature.contains_property("area_km2"));
+        assert!(!feature.contains_property("incomplete"));
+
+        if let OsmObj::Relation(r) = &mut relation {
+            r.refs.push(osmpbfreader::Ref {
+                member: OsmId::Way(WayId(2)),
+                role: "inner".into(),
+            });
+        }
+        let feature = to_feature(&relation, &objs, &options).unwrap();
+        let Some(Value::MultiLineString(lines)) = feature.geometry.as_ref().map(|g| &g.value)
+        else {
+            panic!("expected a multi line string");
+        };
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1][1], [4., 2.]);
+        assert!(!feature.contains_property("incomplete"));
+    }
+
+    #[test]
+    fn write_as_lines_bbox() {
+        use super::{write, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, OsmObj, RelationId, WayId};
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0), (0, 10), (10, 10), (10, 0), (0, 0)]);
+        add_relation(&mut objs, 1, &[(OsmId::Way(WayId(1)), "outer")]);
+        add_tags(
+            objs.get_mut(&OsmId::Relation(RelationId(1))).unwrap(),
+            &[("name", "Musterstadt"), ("admin_level", "8")],
+        );
+
+        let written = |bbox: &str| {
+            let options = Options {
+                as_lines: true,
+                bbox: Some(bbox.parse().unwrap()),
+                ..Options::default()
+            };
+            write(&objs, OsmObj::is_relation, &mut vec![], &options)
+                .unwrap()
+                .written
+        };
+        assert_eq!(written("-1,-1,1,1"), 1);
+        assert_eq!(written("5,-1,6,1"), 1);
+        assert_eq!(written("20,20,30,30"), 0);
+    }
+
+    #[test]
+    fn to_feature_id_source() {
+        use super::{to_feature, IdSource, Options};
+        use crate::util::Objects;
+        use geojson::feature::Id;
+
+        let mut objs = Objects::default();
+        let mut relation = add_relation(&mut objs, 1, &[]);
+        add_tags(
+            &mut relation,
+            &[
+                ("name", "Musterstadt"),
+                ("admin_level", "8"),
+                ("ref", "05111"),
+            ],
+        );
+
+        let id = |id_source, id_tag: Option<&str>| {
+            let options = Options {
+                properties_only: true,
+                id_source,
+                id_tag: id_tag.map(str::to_string),
+                ..Options::default()
+            };
+            to_feature(&relation, &objs, &options).unwrap().id
+        };
+        assert_eq!(
+            id(IdSource::Relation, Some("ref")),
+            Some(Id::Number(1.into()))
+        );
+        assert_eq!(
+            id(IdSource::Ars, Some("ref")),
+            Some(Id::String("05111".to_string()))
+        );
+        assert_eq!(id(IdSource::Ars, None), Some(Id::Number(1.into())));
+        assert_eq!(
+            id(IdSource::Name, None),
+            Some(Id::String("Musterstadt".to_string()))
+        );
+    }
+
+    #[test]
+    fn to_feature_exclude_tags() {
+        use super::{to_feature, Options};
+        use crate::util::Objects;
+
+        let mut objs = Objects::default();
+        let mut relation = add_relation(&mut objs, 1, &[]);
+        add_tags(
+            &mut relation,
+            &[
+                ("name", "Musterstadt"),
+                ("admin_level", "8"),
+                ("source", "survey"),
+                ("population", "1000"),
+            ],
+        );
+
+        let options = Options {
+            properties_only: true,
+            properties: vec!["population".to_string()],
+            all_tags: true,
+            exclude_tags: vec![
+                "source".to_string(),
+                "population".to_string(),
+                "fixme".to_string(),
+            ],
+            ..Options::default()
+        };
+        let feature = to_feature(&relation, &objs, &options).unwrap();
+        assert!(!feature.contains_property("source"));
+        assert!(!feature.contains_property("population"));
+        assert_eq!(feature.property("admin_level"), Some(&"8".into()));
+    }
+
+    #[test]
+    fn to_feature_numeric_tags() {
+        use super::{to_feature, Options};
+        use crate::util::Objects;
+        use serde_json::json;
+
+        let mut objs = Objects::default();
+        let mut relation = add_relation(&mut objs, 1, &[]);
+        add_tags(
+            &mut relation,
+            &[
+                ("name", "Musterstadt"),
+                ("admin_level", "8"),
+                ("population", "1000"),
+                ("area", "12.5"),
+                ("ele", "about 50"),
+                ("postal_code", "01234"),
+            ],
+        );
+
+        let options = Options {
+            properties_only: true,
+            properties: vec!["population".to_string()],
+            all_tags: true,
+            numeric_tags: vec![
+                "population".to_string(),
+                "area".to_string(),
+                "ele".to_string(),
+            ],
+            ..Options::default()
+        };
+        let feature = to_feature(&relation, &objs, &options).unwrap();
+        assert_eq!(feature.property("population"), Some(&json!(1000)));
+        assert_eq!(feature.property("area"), Some(&json!(12.5)));
+        assert_eq!(feature.property("ele"), Some(&json!("about 50")));
+        assert_eq!(feature.property("postal_code"), Some(&json!("01234")));
+    }
+
+    #[test]
+    fn write_min_area() {
+        use super::{write, Options};
+        use crate::util::Objects;
+        use osmpbfreader::OsmObj;
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0), (2, 0), (2, 2), (0, 2), (0, 0)]);
+        add_way(&mut objs, 2, &[(0, 0), (1, 0), (1, 1), (0, 0)]);
+        add_way(&mut objs, 3, &[(0, 0), (1, 1)]);
+        for way in objs.values_mut().filter(|obj| obj.is_way()) {
+            add_tags(way, &[("name", "Platz")]);
+        }
+
+        // About 49000 km², 6000 km² and a line without area.
+        let options = Options {
+            min_area: Some(10_000.),
+            ..Options::default()
+        };
+        let mut out = vec![];
+        let summary = write(&objs, OsmObj::is_way, &mut out, &options).unwrap();
+        assert_eq!(summary.written, 1);
+        assert!(String::from_utf8(out).unwrap().contains(r#""id":1,"#));
+    }
+
+    #[test]
+    fn write_unique_ids() {
+        use super::{write, IdSource, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, OsmObj, RelationId};
+
+        // Relations 1 and 2 share an ARS.
+        let mut objs = Objects::default();
+        for (id, ars) in [(1, "05111"), (2, "05111"), (3, "05112")] {
+            add_relation(&mut objs, id, &[]);
+            add_tags(
+                objs.get_mut(&OsmId::Relation(RelationId(id))).unwrap(),
+                &[
+                    ("name", "Musterstadt"),
+                    ("admin_level", "8"),
+                    ("de:regionalschluessel", ars),
+                ],
+            );
+        }
+
+        let options = Options {
+            properties_only: true,
+            id_source: IdSource::Ars,
+            id_tag: Some(crate::filter::DEFAULT_ID_TAG.to_string()),
+            ..Options::default()
+        };
+        let mut out = vec![];
+        let summary = write(&objs, OsmObj::is_relation, &mut out, &options).unwrap();
+        assert_eq!(summary.written, 2);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches(r#""id":"05111""#).count(), 1);
+        assert_eq!(out.matches(r#""id":"05112""#).count(), 1);
+    }
+
+    #[test]
+    fn write_collection_crs() {
+        use super::{write_collection, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, OsmObj, WayId};
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0), (2, 0), (2, 2), (0, 2), (0, 0)]);
+        add_tags(
+            objs.get_mut(&OsmId::Way(WayId(1))).unwrap(),
+            &[("name", "Platz")],
+        );
+
+        let collection = |options: &Options| {
+            let mut out = vec![];
+            write_collection(&objs, OsmObj::is_way, &mut out, options).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&out).unwrap()
+        };
+
+        assert_eq!(collection(&Options::default()).get("crs"), None);
+        let mut options = Options {
+            emit_crs: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            collection(&options)["crs"]["properties"]["name"],
+            "urn:ogc:def:crs:OGC:1.3:CRS84"
+        );
+        options.lat_lon = true;
+        assert_eq!(
+            collection(&options)["crs"]["properties"]["name"],
+            "urn:ogc:def:crs:EPSG::4326"
+        );
+    }
+
+    #[test]
+    fn write_files_manifest() {
+        use super::{write_files, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{OsmId, OsmObj, WayId};
+        use serde_json::json;
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0), (2, 0), (2, 2), (0, 2), (0, 0)]);
+        add_way(&mut objs, 2, &[(0, 0), (1, 0), (1, 1), (0, 0)]);
+        for way in objs.values_mut().filter(|obj| obj.is_way()) {
+            add_tags(way, &[("name", "Platz")]);
+        }
+        add_tags(
+            objs.get_mut(&OsmId::Way(WayId(2))).unwrap(),
+            &[("de:regionalschluessel", "051110000000")],
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            id_tag: Some(crate::filter::DEFAULT_ID_TAG.to_string()),
+            ..Options::default()
+        };
+        let summary = write_files(&objs, OsmObj::is_way, dir.path(), &options).unwrap();
+        let manifest = std::fs::read_to_string(dir.path().join("manifest.json")).unwrap();
+
+        assert_eq!(summary.written, 2);
+        assert!(dir.path().join("1.geojson").exists());
+        assert!(dir.path().join("051110000000.geojson").exists());
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(
+            manifest,
+            json!([
+                {"file": "1.geojson", "id": 1, "name": "Platz", "ars": null,
+                 "bbox": [0.0, 0.0, 2.0, 2.0]},
+                {"file": "051110000000.geojson", "id": 2, "name": "Platz",
+                 "ars": "051110000000", "bbox": [0.0, 0.0, 1.0, 1.0]},
+            ])
+        );
+    }
+
+    #[test]
+    fn to_feature_way_and_node() {
+        use super::{to_feature, Options};
+        use crate::util::Objects;
+        use geojson::Value;
+        use osmpbfreader::{NodeId, OsmId, WayId};
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0), (0, 1), (1, 1), (0, 0)]);
+        add_way(&mut objs, 2, &[(0, 0), (1, 1)]);
+        for obj in objs.values_mut() {
+            add_tags(obj, &[("name", "Platz")]);
+        }
+        let options = Options::default();
+        let feature = |id| to_feature(&objs[&id], &objs, &options).unwrap();
+
+        // Closed ways become polygons wound counterclockwise.
+        let polygon = feature(OsmId::Way(WayId(1)));
+        assert_eq!(
+            polygon.geometry.as_ref().unwrap().value,
+            Value::Polygon(vec![vec![
+                vec![0., 0.],
+                vec![1., 1.],
+                vec![0., 1.],
+                vec![0., 0.]
+            ]])
+        );
+        assert_eq!(polygon.property("osm_type"), Some(&"way".into()));
+        assert!(polygon.contains_property("area_km2"));
+        assert!(!polygon.contains_property("adminLevel"));
+
+        let line = feature(OsmId::Way(WayId(2)));
+        assert_eq!(
+            line.geometry.as_ref().unwrap().value,
+            Value::LineString(vec![vec![0., 0.], vec![1., 1.]])
+        );
+        assert!(!line.contains_property("area_km2"));
+
+        let point = feature(OsmId::Node(NodeId(101)));
+        assert_eq!(
+            point.geometry.as_ref().unwrap().value,
+            Value::Point(vec![0., 1.])
+        );
+        assert_eq!(point.property("osm_type"), Some(&"node".into()));
+    }
+
+    #[test]
+    fn to_feature_name_prefix() {
+        use super::{to_feature, Options};
+        use crate::util::Objects;
+        use osmpbfreader::{NodeId, OsmId};
+
+        let mut objs = Objects::default();
+        add_way(&mut objs, 1, &[(0, 0)]);
+        let id = OsmId::Node(NodeId(100));
+        add_tags(
+            objs.get_mut(&id).unwrap(),
+            &[("name", "Musterstadt"), ("name:prefix", "Stadt")],
+        );
+
+        let feature = to_feature(&objs[&id], &objs, &Options::default()).unwrap();
+        assert_eq!(feature.property("name"), Some(&"Musterstadt".into()));
+        assert_eq!(
+            feature.property("display_name"),
+            Some(&"Stadt Musterstadt".into())
+        );
+
+        let options = Options {
+            merge_name_prefix: true,
+            ..Options::default()
+        };
+        let feature = to_feature(&objs[&id], &objs, &options).unwrap();
+        assert_eq!(feature.property("name"), Some(&"Stadt Musterstadt".into()));
+        assert!(!feature.contains_property("display_name"));
+    }
+
+    #[test]
+    fn split_antimeridian() {
+        use super::split_antimeridian;
+
+        let ring = |coords: &[(f64, f64)]| {
+            Line(coords.iter().map(|&(x, y)| Position::new(x, y)).collect())
+        };
+
+        let inside = ring(&[(170., 0.), (175., 0.), (175., 10.), (170., 0.)]);
+        assert_eq!(split_antimeridian(&[inside]), None);
+
+        let crossing = ring(&[
+            (170., 0.),
+            (-170., 0.),
+            (-170., 10.),
+            (170., 10.),
+            (170., 0.),
+        ]);
+        let hole = ring(&[
+            (-175., 2.),
+            (-175., 4.),
+            (175., 4.),
+            (175., 2.),
+            (-175., 2.),
+        ]);
+        let polygons = split_antimeridian(&[crossing, hole]).unwrap();
+
+        assert_eq!(
+            polygons,
+            vec![
+                vec![
+                    ring(&[(180., 0.), (180., 10.), (170., 10.), (170., 0.), (180., 0.)]),
+                    ring(&[(180., 4.), (175., 4.), (175., 2.), (180., 2.), (180., 4.)]),
+                ],
+                vec![
+                    ring(&[
+                        (-180., 0.),
+                        (-170., 0.),
+                        (-170., 10.),
+                        (-180., 10.),
+                        (-180., 0.)
+                    ]),
+                    ring(&[
+                        (-175., 4.),
+                        (-180., 4.),
+                        (-180., 2.),
+                        (-175., 2.),
+                        (-175., 4.)
+                    ]),
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn bbox_parse() {
+        use super::BBox;
+
+        assert_eq!(
+            "-1.5,2,3,4".parse::<BBox>().unwrap(),
+            BBox {
+                min_lon: -1.5,
+                min_lat: 2.,
+                max_lon: 3.,
+                max_lat: 4.,
+            }
+        );
+        assert!("1,2,3".parse::<BBox>().is_err());
+        assert!("1,2,3,x".parse::<BBox>().is_err());
+        assert!("3,2,1,4".parse::<BBox>().is_err());
+    }
+
+    #[test]
+    fn bbox_of() {
+        use super::BBox;
+
+        let polygon = |coords: &[(f64, f64)]| {
+            vec![coords.iter().map(|&(x, y)| vec![x, y]).collect::<Vec<_>>()]
+        };
+
+        assert_eq!(
+            BBox::of(&geojson::Value::MultiPolygon(vec![
+                polygon(&[(0., 0.), (1., -1.), (0.5, 2.), (0., 0.)]),
+                polygon(&[(5., 5.), (6., 5.), (6., 6.), (5., 5.)]),
+            ])),
+            Some(BBox {
+                min_lon: 0.,
+                min_lat: -1.,
+                max_lon: 6.,
+                max_lat: 6.,
+            })
+        );
+        assert_eq!(BBox::of(&geojson::Value::Polygon(vec![])), None);
+    }
+
+    #[test]
+    fn sort_by() {
+        use super::SortBy;
+
+        let feature = |id: u64, name: &str, admin_level: Option<u8>| {
+            let mut feature = geojson::Feature {
+                id: Some(geojson::feature::Id::Number(id.into())),
+                ..geojson::Feature::default()
+            };
+            feature.set_property("name", name);
+            if let Some(level) = admin_level {
+                feature.set_property("adminLevel", level);
+            }
+            feature
+        };
+        let mut features = vec![
+            feature(3, "Aachen", Some(8)),
+            feature(1, "Bonn", None),
+            feature(2, "Aachen", Some(6)),
+        ];
+        let ids = |features: &[geojson::Feature]| {
+            features
+                .iter()
+                .map(|f| f.id.clone().unwrap())
+                .map(|id| match id {
+                    geojson::feature::Id::Number(n) => n.as_u64().unwrap(),
+                    geojson::feature::Id::String(_) => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        SortBy::Id.sort(&mut features);
+        assert_eq!(ids(&features), [1, 2, 3]);
+        SortBy::Name.sort(&mut features);
+        assert_eq!(ids(&features), [2, 3, 1]);
+        SortBy::AdminLevel.sort(&mut features);
+        assert_eq!(ids(&features), [2, 3, 1]);
+        features[2].set_property("adminLevel", 4);
+        SortBy::AdminLevel.sort(&mut features);
+        assert_eq!(ids(&features), [1, 2, 3]);
+    }
+
+    #[test]
+    fn mask_selects() {
+        use super::Mask;
+        use geojson::Value;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mask.geojson");
+        std::fs::write(
+            &path,
+            r#"{"type": "Feature", "properties": {}, "geometry": {"type": "Polygon", "coordinates": [
+                [[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]],
+                [[4, 4], [6, 4], [6, 6], [4, 6], [4, 4]]
+            ]}}"#,
+        )
+        .unwrap();
+        let mask = Mask::from_file(path.to_str().unwrap()).unwrap();
+
+        let square = |x: f64, y: f64| {
+            Value::Polygon(vec![vec![
+                vec![x, y],
+                vec![x + 1., y],
+                vec![x + 1., y + 1.],
+                vec![x, y + 1.],
+                vec![x, y],
+            ]])
+        };
+        assert!(mask.selects(&square(1., 1.)));
+        // Centroids in the hole or outside are not selected, even if the polygon overlaps.
+        assert!(!mask.selects(&square(4.5, 4.5)));
+        assert!(!mask.selects(&square(9.8, 1.)));
+        // Other geometries need any position inside.
+        assert!(mask.selects(&Value::LineString(vec![vec![-1., 1.], vec![1., 1.]])));
+        assert!(!mask.selects(&Value::Point(vec![5., 5.])));
+    }
+
+    #[test]
+    fn buffer_size() {
+        use super::Options;
+        use std::io::BufWriter;
+
+        let options = Options {
+            buffer_size: Some(1 << 20),
+            ..Options::default()
+        };
+        assert_eq!(options.buffer(vec![]).capacity(), 1 << 20);
+        assert_eq!(
+            Options::default().buffer(vec![]).capacity(),
+            BufWriter::new(vec![]).capacity()
+        );
+    }
+
+    #[test]
+    fn bbox_union() {
+        use super::BBox;
+
+        let a = BBox {
+            min_lon: 0.,
+            min_lat: 0.,
+            max_lon: 1.,
+            max_lat: 1.,
+        };
+        let b = BBox {
+            min_lon: -2.,
+            min_lat: 0.5,
+            max_lon: 0.5,
+            max_lat: 3.,
+        };
+
+        let expected = BBox {
+            min_lon: -2.,
+            min_lat: 0.,
+            max_lon: 1.,
+            max_lat: 3.,
+        };
+        assert_eq!(a.union(&b), expected);
+        assert_eq!(b.union(&a), expected);
+        assert_eq!(a.union(&a), a);
+    }
+
+    #[test]
+    fn swap_coordinates() {
+        use super::swap_coordinates;
+        use geojson::{Feature, Geometry, Value};
+        use serde_json::json;
+
+        let mut feature = Feature {
+            geometry: Some(Geometry::new(Value::Polygon(vec![vec![
+                vec![7., 50.],
+                vec![8., 50.],
+                vec![8., 51.],
+                vec![7., 50.],
+            ]]))),
+            bbox: Some(vec![7., 50., 8., 51.]),
+            ..Feature::default()
+        };
+        feature.set_property("center", json!([7.5, 50.5]));
+
+        swap_coordinates(&mut feature);
+        assert_eq!(
+            feature.geometry.as_ref().unwrap().value,
+            Value::Polygon(vec![vec![
+                vec![50., 7.],
+                vec![50., 8.],
+                vec![51., 8.],
+                vec![50., 7.],
+            ]])
+        );
+        assert_eq!(feature.bbox, Some(vec![50., 7., 51., 8.]));
+        assert_eq!(feature.property("center"), Some(&json!([50.5, 7.5])));
+    }
+
+    #[test]
+    fn bbox_overlaps() {
+        use super::BBox;
+
+        let bbox = |min_lon, min_lat, max_lon, max_lat| BBox {
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+        };
+        let a = bbox(0., 0., 2., 2.);
+
+        assert!(a.overlaps(&bbox(1., 1., 3., 3.)));
+        assert!(a.overlaps(&bbox(0.5, 0.5, 1., 1.)));
+        assert!(bbox(0.5, 0.5, 1., 1.).overlaps(&a));
+        assert!(a.overlaps(&bbox(2., 0., 3., 1.)), "touching edges overlap");
+        assert!(!a.overlaps(&bbox(3., 0., 4., 1.)));
+        assert!(!a.overlaps(&bbox(0., -2., 2., -1.)));
+    }
+
+    #[test]
+    fn bbox_intersects() {
+        use super::BBox;
+
+        let square = |x0: f64, y0: f64, x1: f64, y1: f64| {
+            geojson::Value::Polygon(vec![vec![
+                vec![x0, y0],
+                vec![x1, y0],
+                vec![x1, y1],
+                vec![x0, y1],
+                vec![x0, y0],
+            ]])
+        };
+        let bbox: BBox = "0,0,1,1".parse().unwrap();
+
+        // Vertex inside the box.
+        assert!(bbox.intersects(&square(0.5, 0.5, 2., 2.)));
+        // Box inside the polygon.
+        assert!(bbox.intersects(&square(-1., -1., 2., 2.)));
+        // Only edges cross.
+        assert!(bbox.intersects(&geojson::Value::Polygon(vec![vec![
+            vec![-1., 0.5],
+            vec![0.5, -1.],
+            vec![2., 0.5],
+            vec![0.5, 2.],
+            vec![-1., 0.5],
+        ]])));
+        // Disjoint.
+        assert!(!bbox.intersects(&square(2., 2., 3., 3.)));
+
+        let point = |x: f64, y: f64| geojson::Value::Point(vec![x, y]);
+        assert!(bbox.intersects(&point(0.5, 0.5)));
+        assert!(bbox.intersects(&point(1., 0.)));
+        assert!(!bbox.intersects(&point(2., 0.5)));
+        assert!(bbox.intersects(&geojson::Value::MultiPoint(vec![
+            vec![2., 2.],
+            vec![0.5, 0.5],
+        ])));
+
+        let line = |ps: &[(f64, f64)]| ps.iter().map(|&(x, y)| vec![x, y]).collect::<Vec<_>>();
+        // Vertex inside the box.
+        assert!(bbox.intersects(&geojson::Value::LineString(line(&[(0.5, 0.5), (2., 2.)]))));
+        // Only a segment crosses the box.
+        assert!(bbox.intersects(&geojson::Value::LineString(line(&[(-1., 0.5), (2., 0.5)]))));
+        // Disjoint, although the box lies within the line's envelope.
+        assert!(!bbox.intersects(&geojson::Value::LineString(line(&[
+            (-1., 2.),
+            (2., 2.),
+            (2., -1.)
+        ]))));
+        assert!(bbox.intersects(&geojson::Value::MultiLineString(vec![
+            line(&[(2., 2.), (3., 3.)]),
+            line(&[(0.5, -1.), (0.5, 2.)]),
+        ])));
+        assert!(
+            !bbox.intersects(&geojson::Value::MultiLineString(vec![line(&[
+                (2., 2.),
+                (3., 3.)
+            ])]))
+        );
+    }
+
+    #[test]
+    fn area_km2() {
+        use super::{area_km2, EARTH_RADIUS_KM};
+
+        let square = |min: f64, max: f64| {
+            vec![
+                vec![min, min],
+                vec![max, min],
+                vec![max, max],
+                vec![min, max],
+                vec![min, min],
+            ]
+        };
+        // Exact area of the cell between two meridians and two parallels.
+        let cell = |min: f64, max: f64| {
+            EARTH_RADIUS_KM.powi(2)
+                * (max - min).to_radians()
+                * (max.to_radians().sin() - min.to_radians().sin())
+        };
+
+        let polygon = geojson::Value::Polygon(vec![square(0., 1.)]);
+        assert!((area_km2(&polygon) - cell(0., 1.)).abs() < 1e-6);
+
+        // Winding doesn't matter.
+        let mut reversed = square(0., 1.);
+        reversed.reverse();
+        let polygon = geojson::Value::Polygon(vec![reversed]);
+        assert!((area_km2(&polygon) - cell(0., 1.)).abs() < 1e-6);
+
+        let with_hole = geojson::Value::Polygon(vec![square(0., 2.), square(0.5, 1.)]);
+        assert!((area_km2(&with_hole) - (cell(0., 2.) - cell(0.5, 1.))).abs() < 1e-6);
+
+        let multi = geojson::Value::MultiPolygon(vec![vec![square(0., 1.)], vec![square(2., 3.)]]);
+        assert!((area_km2(&multi) - (cell(0., 1.) + cell(2., 3.))).abs() < 1e-6);
+
+        assert!(area_km2(&geojson::Value::Point(vec![0., 0.])).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn center() {
+        use super::{centroid, point_on_surface};
+
+        let square = geojson::Value::Polygon(vec![vec![
+            vec![0., 0.],
+            vec![2., 0.],
+            vec![2., 2.],
+            vec![0., 2.],
+            vec![0., 0.],
+        ]]);
+        assert_eq!(centroid(&square), Some([1., 1.]));
+        assert_eq!(point_on_surface(&square), Some([1., 1.]));
+
+        // A "U" whose centroid lies in the gap between its arms.
+        let u = geojson::Value::Polygon(vec![vec![
+            vec![0., 0.],
+            vec![3., 0.],
+            vec![3., 3.],
+            vec![2., 3.],
+            vec![2., 1.],
+            vec![1., 1.],
+            vec![1., 3.],
+            vec![0., 3.],
+            vec![0., 0.],
+        ]]);
+        let [lon, lat] = centroid(&u).unwrap();
+        assert!(lon > 1. && lon < 2. && lat > 1.);
+        let [lon, lat] = point_on_surface(&u).unwrap();
+        assert!(!(1. ..=2.).contains(&lon) && lat > 1.);
+
+        // The point avoids holes.
+        let with_hole = geojson::Value::Polygon(vec![
+            vec![
+                vec![0., 0.],
+                vec![4., 0.],
+                vec![4., 2.],
+                vec![0., 2.],
+                vec![0., 0.],
+            ],
+            vec![
+                vec![1., 0.5],
+                vec![1., 1.5],
+                vec![2.5, 1.5],
+                vec![2.5, 0.5],
+                vec![1., 0.5],
+            ],
+        ]);
+        assert_eq!(point_on_surface(&with_hole), Some([3.25, 1.]));
+
+        assert_eq!(centroid(&geojson::Value::Point(vec![0., 0.])), None);
+    }
+
+    #[test]
+    fn options_round() {
+        use super::Options;
+
+        let options = Options::default();
+        assert_eq!(options.round(7.123_456_7), 7.123_456_7);
+
+        let options = Options {
+            precision: Some(3),
+            ..Options::default()
+        };
+        assert_eq!(options.round(7.123_456_7), 7.123);
+        assert_eq!(options.round(-7.123_56), -7.124);
+    }
+
+    #[test]
+    fn parse_admin_level() {
+        use super::{parse_admin_level, AdminLevelFallback};
+        use serde_json::json;
+
+        let parse = |value, fallback| parse_admin_level(value, fallback).ok();
+
+        assert_eq!(parse("8", AdminLevelFallback::Fail), Some(json!(8)));
+        assert_eq!(parse("8;9", AdminLevelFallback::First), Some(json!(8)));
+        assert_eq!(parse("yes", AdminLevelFallback::First), Some(json!("yes")));
+        assert_eq!(parse("8;9", AdminLevelFallback::Raw), Some(json!("8;9")));
+        assert_eq!(parse("8;9", AdminLevelFallback::Fail), None);
+    }
+
+    #[test]
+    fn name_of() {
+        use super::name_of;
+        use osmpbfreader::Tags;
+
+        let mut tags = Tags::new();
+        tags.insert("name".into(), "München".into());
+        tags.insert("name:en".into(), "Munich".into());
+        let langs = |langs: &[&str]| langs.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        assert_eq!(name_of(&tags, &[]), Some("München"));
+        assert_eq!(name_of(&tags, &langs(&["en"])), Some("Munich"));
+        assert_eq!(name_of(&tags, &langs(&["fr", "en"])), Some("Munich"));
+        assert_eq!(
+            name_of(&tags, &langs(&["de", "name", "en"])),
+            Some("München")
+        );
+        assert_eq!(name_of(&tags, &langs(&["fr"])), Some("München"));
+        assert_eq!(name_of(&Tags::new(), &langs(&["en"])), None);
+    }
+
+    mod multi_map {
+        use super::super::MultiMap;
+
+        #[test]
+        fn get() {
+            let mut m = MultiMap::default();
+            m.insert(1, 12);
+            m.insert(2, 12);
 
             assert_eq!(m.get(&1), Some(&12));
             assert_eq!(m.get(&2), Some(&12));
         }
 
+        #[test]
+        fn get_smallest() {
+            let mut m = MultiMap::default();
+            for x in [20, 10, 30] {
+                m.insert(1, x);
+            }
+
+            assert_eq!(m.get(&1), Some(&10));
+            assert_eq!(m.consume_one(&1), Some(10));
+            assert_eq!(m.get(&1), Some(&20));
+        }
+
         #[test]
         fn consume_one() {
             let mut m = MultiMap::default();
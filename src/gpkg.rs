@@ -0,0 +1,283 @@
+use std::path::Path;
+
+use anyhow::Result;
+use geojson::{Feature, Position, Value};
+use osmpbfreader::OsmObj;
+use rusqlite::{params, types, Connection};
+
+use crate::{
+    geom::{self, BBox},
+    util::Objects,
+};
+
+/// Name of the feature table.
+const TABLE: &str = "localities";
+
+/// Spatial reference system of all geometries, WGS 84.
+const SRS_ID: i32 = 4326;
+
+/// Tables required by the `GeoPackage` specification, and the feature table.
+const SCHEMA: &str = r#"
+PRAGMA application_id = 1196444487;
+PRAGMA user_version = 10300;
+
+CREATE TABLE gpkg_spatial_ref_sys (
+    srs_name TEXT NOT NULL,
+    srs_id INTEGER PRIMARY KEY,
+    organization TEXT NOT NULL,
+    organization_coordsys_id INTEGER NOT NULL,
+    definition TEXT NOT NULL,
+    description TEXT
+);
+INSERT INTO gpkg_spatial_ref_sys VALUES
+    ('Undefined cartesian SRS', -1, 'NONE', -1, 'undefined', 'undefined cartesian coordinate reference system'),
+    ('Undefined geographic SRS', 0, 'NONE', 0, 'undefined', 'undefined geographic coordinate reference system'),
+    ('WGS 84 geodetic', 4326, 'EPSG', 4326,
+     'GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563,AUTHORITY["EPSG","7030"]],AUTHORITY["EPSG","6326"]],PRIMEM["Greenwich",0,AUTHORITY["EPSG","8901"]],UNIT["degree",0.0174532925199433,AUTHORITY["EPSG","9122"]],AUTHORITY["EPSG","4326"]]',
+     'longitude/latitude coordinates in decimal degrees on the WGS 84 spheroid');
+
+CREATE TABLE gpkg_contents (
+    table_name TEXT NOT NULL PRIMARY KEY,
+    data_type TEXT NOT NULL,
+    identifier TEXT UNIQUE,
+    description TEXT DEFAULT '',
+    last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+    min_x DOUBLE,
+    min_y DOUBLE,
+    max_x DOUBLE,
+    max_y DOUBLE,
+    srs_id INTEGER REFERENCES gpkg_spatial_ref_sys(srs_id)
+);
+
+CREATE TABLE gpkg_geometry_columns (
+    table_name TEXT NOT NULL REFERENCES gpkg_contents(table_name),
+    column_name TEXT NOT NULL,
+    geometry_type_name TEXT NOT NULL,
+    srs_id INTEGER NOT NULL REFERENCES gpkg_spatial_ref_sys(srs_id),
+    z TINYINT NOT NULL,
+    m TINYINT NOT NULL,
+    PRIMARY KEY (table_name, column_name)
+);
+
+CREATE TABLE localities (
+    fid INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    geom GEOMETRY,
+    name TEXT,
+    admin_level INTEGER,
+    ars TEXT
+);
+INSERT INTO gpkg_contents (table_name, data_type, identifier, srs_id)
+    VALUES ('localities', 'features', 'localities', 4326);
+INSERT INTO gpkg_geometry_columns VALUES ('localities', 'geom', 'GEOMETRY', 4326, 0, 0);
+"#;
+
+/// Write the features for all objects in `objs` matching `pred` to a new `GeoPackage` at `path`,
+/// with name, admin level and `ars` as columns. An existing file at `path` must be empty.
+///
+/// # Errors
+///
+/// Fails if the database cannot be written or, with `options.strict`, if an object cannot be
+/// converted.
+pub fn write(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    path: &Path,
+    options: &geom::Options,
+) -> Result<geom::Summary> {
+    let mut connection = Connection::open(path)?;
+    connection.execute_batch(SCHEMA)?;
+
+    let transaction = connection.transaction()?;
+    let mut summary = geom::Summary::default();
+    let mut bbox: Option<BBox> = None;
+
+    {
+        let mut insert = transaction.prepare(&format!(
+            "INSERT INTO {TABLE} (geom, name, admin_level, ars) VALUES (?1, ?2, ?3, ?4)"
+        ))?;
+
+        for feature in geom::features(objs, pred, options, &mut summary.skipped) {
+            let feature = feature?;
+            let Some(geometry) = &feature.geometry else {
+                continue;
+            };
+            let envelope = BBox::of(&geometry.value);
+            if let Some(b) = envelope {
+                bbox = Some(bbox.map_or(b, |bbox| bbox.union(&b)));
+            }
+
+            insert.execute(params![
+                to_blob(&geometry.value, envelope),
+                column(&feature, "name"),
+                column(&feature, "adminLevel"),
+                column(&feature, "ars"),
+            ])?;
+            summary.written += 1;
+        }
+    }
+
+    if let Some(bbox) = bbox {
+        transaction.execute(
+            "UPDATE gpkg_contents SET min_x = ?1, min_y = ?2, max_x = ?3, max_y = ?4 \
+             WHERE table_name = ?5",
+            params![
+                bbox.min_lon,
+                bbox.min_lat,
+                bbox.max_lon,
+                bbox.max_lat,
+                TABLE
+            ],
+        )?;
+    }
+    transaction.commit()?;
+
+    Ok(summary)
+}
+
+/// The column value for the property `key` of `feature`.
+fn column(feature: &Feature, key: &str) -> types::Value {
+    match feature.property(key) {
+        None | Some(serde_json::Value::Null) => types::Value::Null,
+        Some(serde_json::Value::String(s)) => types::Value::Text(s.clone()),
+        Some(serde_json::Value::Number(n)) => match n.as_i64() {
+            Some(n) => types::Value::Integer(n),
+            None => types::Value::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Some(value) => types::Value::Text(value.to_string()),
+    }
+}
+
+/// Encode `geometry` as `GeoPackage` binary: a header with the `envelope`, followed by WKB.
+fn to_blob(geometry: &Value, envelope: Option<BBox>) -> Vec<u8> {
+    let mut blob = b"GP".to_vec();
+    // Version 1 (stored as 0), little endian, and an envelope of four values or empty geometry.
+    blob.push(0);
+    blob.push(if envelope.is_some() { 0b0011 } else { 0b1_0001 });
+    blob.extend(SRS_ID.to_le_bytes());
+    if let Some(b) = envelope {
+        for value in [b.min_lon, b.max_lon, b.min_lat, b.max_lat] {
+            blob.extend(value.to_le_bytes());
+        }
+    }
+    wkb(geometry, &mut blob);
+    blob
+}
+
+/// Append `geometry` as little endian well-known binary to `out`.
+fn wkb(geometry: &Value, out: &mut Vec<u8>) {
+    fn header(out: &mut Vec<u8>, kind: u32) {
+        out.push(1);
+        out.extend(kind.to_le_bytes());
+    }
+    fn count(out: &mut Vec<u8>, n: usize) {
+        out.extend(
+            u32::try_from(n)
+                .expect("WKB counts fit into u32")
+                .to_le_bytes(),
+        );
+    }
+    fn point(out: &mut Vec<u8>, p: &Position) {
+        out.extend(p[0].to_le_bytes());
+        out.extend(p[1].to_le_bytes());
+    }
+    fn points(out: &mut Vec<u8>, ps: &[Position]) {
+        count(out, ps.len());
+        for p in ps {
+            point(out, p);
+        }
+    }
+    fn polygon(out: &mut Vec<u8>, rings: &[Vec<Position>]) {
+        header(out, 3);
+        count(out, rings.len());
+        for ring in rings {
+            points(out, ring);
+        }
+    }
+
+    match geometry {
+        Value::Point(p) => {
+            header(out, 1);
+            point(out, p);
+        }
+        Value::LineString(ps) => {
+            header(out, 2);
+            points(out, ps);
+        }
+        Value::Polygon(rings) => polygon(out, rings),
+        Value::MultiPoint(ps) => {
+            header(out, 4);
+            count(out, ps.len());
+            for p in ps {
+                header(out, 1);
+                point(out, p);
+            }
+        }
+        Value::MultiLineString(lines) => {
+            header(out, 5);
+            count(out, lines.len());
+            for ps in lines {
+                header(out, 2);
+                points(out, ps);
+            }
+        }
+        Value::MultiPolygon(polygons) => {
+            header(out, 6);
+            count(out, polygons.len());
+            for rings in polygons {
+                polygon(out, rings);
+            }
+        }
+        Value::GeometryCollection(geometries) => {
+            header(out, 7);
+            count(out, geometries.len());
+            for g in geometries {
+                wkb(&g.value, out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use geojson::Value;
+
+    use super::to_blob;
+    use crate::geom::BBox;
+
+    #[test]
+    fn point_blob() {
+        let point = Value::Point(vec![1., 2.]);
+        let blob = to_blob(&point, BBox::of(&point));
+
+        let mut expected = vec![b'G', b'P', 0, 0b0011];
+        expected.extend(4326_i32.to_le_bytes());
+        for value in [1_f64, 1., 2., 2.] {
+            expected.extend(value.to_le_bytes());
+        }
+        expected.extend([1, 1, 0, 0, 0]);
+        expected.extend(1_f64.to_le_bytes());
+        expected.extend(2_f64.to_le_bytes());
+
+        assert_eq!(blob, expected);
+    }
+
+    #[test]
+    fn polygon_wkb() {
+        let mut out = vec![];
+        super::wkb(
+            &Value::Polygon(vec![vec![
+                vec![0., 0.],
+                vec![1., 0.],
+                vec![1., 1.],
+                vec![0., 0.],
+            ]]),
+            &mut out,
+        );
+
+        // Byte order and type, one ring of four points with two coordinates each.
+        assert_eq!(out[..5], [1, 3, 0, 0, 0]);
+        assert_eq!(out[5..9], 1_u32.to_le_bytes());
+        assert_eq!(out[9..13], 4_u32.to_le_bytes());
+        assert_eq!(out.len(), 13 + 4 * 16);
+    }
+}
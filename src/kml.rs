@@ -0,0 +1,159 @@
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+};
+
+use anyhow::Result;
+use geojson::{PolygonType, Position, Value};
+use itertools::Itertools;
+use osmpbfreader::OsmObj;
+
+use crate::{geom, util::Objects};
+
+/// Write a KML document with a placemark named after the `name` property for each object in
+/// `objs` matching `pred`.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails or, with `options.strict`, if an object cannot be converted.
+pub fn write(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    out: impl io::Write,
+    options: &geom::Options,
+) -> Result<geom::Summary> {
+    // Use a buffered writer to amortize flushes.
+    let mut buffer = options.buffer(out);
+    let mut summary = geom::Summary::default();
+
+    writeln!(buffer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(buffer, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(buffer, "<Document>")?;
+
+    for feature in geom::features(objs, pred, options, &mut summary.skipped) {
+        let feature = feature?;
+        let Some(geometry) = &feature.geometry else {
+            continue;
+        };
+        let name = match feature.property("name") {
+            Some(serde_json::Value::String(name)) => name.as_str(),
+            _ => "",
+        };
+
+        writeln!(
+            buffer,
+            "<Placemark><name>{}</name>{}</Placemark>",
+            escape(name),
+            to_kml(&geometry.value)
+        )?;
+        summary.written += 1;
+    }
+
+    writeln!(buffer, "</Document>")?;
+    writeln!(buffer, "</kml>")?;
+
+    Ok(summary)
+}
+
+/// Serialize a `geojson` geometry as KML geometry element.
+#[must_use]
+pub fn to_kml(geometry: &Value) -> String {
+    match geometry {
+        Value::Point(p) => format!("<Point>{}</Point>", coordinates(std::slice::from_ref(p))),
+        Value::MultiPoint(ps) => multi_geometry(ps.iter().map(|p| Value::Point(p.clone()))),
+        Value::LineString(ps) => format!("<LineString>{}</LineString>", coordinates(ps)),
+        Value::MultiLineString(lines) => {
+            multi_geometry(lines.iter().map(|ps| Value::LineString(ps.clone())))
+        }
+        Value::Polygon(rings) => polygon(rings),
+        Value::MultiPolygon(polygons) => {
+            multi_geometry(polygons.iter().map(|rings| Value::Polygon(rings.clone())))
+        }
+        Value::GeometryCollection(geometries) => {
+            multi_geometry(geometries.iter().map(|g| g.value.clone()))
+        }
+    }
+}
+
+fn coordinates(ps: &[Position]) -> String {
+    format!(
+        "<coordinates>{}</coordinates>",
+        ps.iter().map(|p| p.iter().join(",")).join(" ")
+    )
+}
+
+fn polygon(rings: &PolygonType) -> String {
+    let ring = |ring: &Vec<Position>| format!("<LinearRing>{}</LinearRing>", coordinates(ring));
+
+    format!(
+        "<Polygon>{}{}</Polygon>",
+        rings
+            .first()
+            .map(|outer| format!("<outerBoundaryIs>{}</outerBoundaryIs>", ring(outer)))
+            .unwrap_or_default(),
+        rings
+            .iter()
+            .skip(1)
+            .map(|inner| format!("<innerBoundaryIs>{}</innerBoundaryIs>", ring(inner)))
+            .join("")
+    )
+}
+
+fn multi_geometry(geometries: impl Iterator<Item = Value>) -> String {
+    format!(
+        "<MultiGeometry>{}</MultiGeometry>",
+        geometries.map(|g| to_kml(&g)).join("")
+    )
+}
+
+/// Escape the characters with special meaning in XML text.
+fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>']) {
+        return Cow::Borrowed(text);
+    }
+
+    Cow::Owned(
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;"),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use geojson::Value;
+
+    use super::{escape, to_kml};
+
+    #[test]
+    fn polygon() {
+        let outer = vec![vec![0., 0.], vec![2., 0.], vec![2., 2.], vec![0., 0.]];
+        let inner = vec![vec![1., 0.5], vec![1.5, 1.], vec![1.5, 0.5], vec![1., 0.5]];
+
+        assert_eq!(
+            to_kml(&Value::Polygon(vec![outer.clone(), inner])),
+            "<Polygon>\
+             <outerBoundaryIs><LinearRing>\
+             <coordinates>0,0 2,0 2,2 0,0</coordinates>\
+             </LinearRing></outerBoundaryIs>\
+             <innerBoundaryIs><LinearRing>\
+             <coordinates>1,0.5 1.5,1 1.5,0.5 1,0.5</coordinates>\
+             </LinearRing></innerBoundaryIs>\
+             </Polygon>"
+        );
+        assert_eq!(
+            to_kml(&Value::MultiPolygon(vec![vec![outer]])),
+            "<MultiGeometry><Polygon>\
+             <outerBoundaryIs><LinearRing>\
+             <coordinates>0,0 2,0 2,2 0,0</coordinates>\
+             </LinearRing></outerBoundaryIs>\
+             </Polygon></MultiGeometry>"
+        );
+    }
+
+    #[test]
+    fn escape_name() {
+        assert_eq!(escape("Musterstadt"), "Musterstadt");
+        assert_eq!(escape("Stadt <A & B>"), "Stadt &lt;A &amp; B&gt;");
+    }
+}
@@ -0,0 +1,53 @@
+//! Filtering and extracting locality data from OSM files.
+//!
+//! Load objects with [`load_relations`], select them with the predicates in [`filter`] and
+//! convert them with [`features`] or single relations with [`to_feature`], or write all matching
+//! relations in one of the output formats.
+
+use anyhow::Result;
+use osmpbfreader::OsmObj;
+
+pub mod csv;
+pub mod filter;
+pub mod geom;
+pub mod gpkg;
+pub mod kml;
+pub mod stats;
+pub mod topojson;
+pub mod util;
+pub mod wkt;
+pub mod xml;
+
+pub use geom::to_feature;
+pub use util::load_relations;
+
+/// Convert each object in `objs` matching `pred` to a feature, ordered by id. Unlike the
+/// writers, this neither logs nor skips objects which cannot be converted, but yields their
+/// errors. `options.bbox` and `options.limit` are not applied.
+///
+/// ```no_run
+/// use osmtools::{features, filter, geom, load_relations};
+///
+/// let by_target = filter::by_target(&filter::DEFAULT_ADMIN_LEVELS, Some(filter::DEFAULT_ID_TAG));
+/// let objs = load_relations(&["germany.osm.pbf".into()], &by_target, false)?;
+///
+/// for feature in features(&objs, &by_target, &geom::Options::default()) {
+///     match feature {
+///         Ok(feature) => println!("{feature}"),
+///         Err(e) => eprintln!("{e}"),
+///     }
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn features<'a>(
+    objs: &'a util::Objects,
+    pred: impl Fn(&OsmObj) -> bool + 'a,
+    options: &'a geom::Options,
+) -> impl Iterator<Item = Result<geojson::Feature>> + 'a {
+    let positions = geom::node_positions(objs);
+
+    util::matching(objs, pred).into_iter().map(move |obj| {
+        let position = |id| positions.get(&id).copied();
+        geom::convert(obj, objs, &position, options)
+    })
+}
@@ -1,105 +1,827 @@
-mod filter;
-mod geom;
-mod stats;
-mod util;
-
 use std::{
-    io::{self, stdout, BufWriter, Write},
-    path::PathBuf,
+    io::{self, stdout, Write},
+    path::{Path, PathBuf},
 };
 
-use anyhow::{bail, Result};
-use clap::{Parser, Subcommand};
-use log::info;
+use anyhow::{bail, Context, Result};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use flate2::{write::GzEncoder, Compression};
+use log::{info, LevelFilter};
 use osmpbfreader::OsmObj;
+use osmtools::{csv, filter, geom, gpkg, kml, stats, topojson, util, wkt};
 use simple_logger::SimpleLogger;
 
 #[derive(Parser)]
+#[command(
+    subcommand_negates_reqs = true,
+    override_usage = "osmtools [OPTIONS] --in-file <IN_FILE>... [COMMAND]\n       osmtools completions <SHELL>"
+)]
+#[allow(clippy::struct_excessive_bools)]
 struct Cli {
-    /// PBF file to read.
-    #[arg(short, long)]
-    in_file: PathBuf,
+    /// PBF or OSM XML files to read, optionally compressed with gzip or bzip2. Objects from all
+    /// files are combined into a single output.
+    #[arg(short, long, required = true, num_args = 1..)]
+    in_file: Vec<PathBuf>,
 
     /// Path to output file. If unspecified output is written to stdout.
     #[arg(short, long)]
     out_file: Option<PathBuf>,
 
-    /// Output format.
-    #[arg(short, long, value_parser=["geojson", "raw"], default_value = "geojson")]
+    /// Write each feature to its own file "<ars>.geojson" in this directory, or "<id>.geojson"
+    /// without `ars`, and list them in "manifest.json". Only for geojson output.
+    #[arg(long, conflicts_with_all = ["out_file", "stream"])]
+    out_dir: Option<PathBuf>,
+
+    /// Append to the output file instead of overwriting it. The csv header is only written to
+    /// an empty file. Only for line-based output: geojson, geojson-seq, csv, wkt and raw.
+    #[arg(long, requires = "out_file", conflicts_with = "pretty")]
+    append: bool,
+
+    /// Compress the output with gzip. Implied if the output file ends in ".gz".
+    #[arg(long)]
+    gzip: bool,
+
+    /// Output format. By default inferred from the extension of the output file, or geojson.
+    #[arg(short, long, value_parser=["geojson", "geojson-seq", "geojson-collection", "topojson", "csv", "wkt", "kml", "gpkg", "raw"])]
     format: Option<String>,
 
+    /// Only write id, type and tags of each object in raw output, not its members or nodes.
+    #[arg(long)]
+    raw_tags_only: bool,
+
+    /// Only write the properties of each feature without converting its geometry, as a JSON
+    /// object per line in geojson output or as csv. Much faster for building lookup tables.
+    #[arg(long, conflicts_with_all = ["out_dir", "stream", "pretty", "bbox", "clip_mask", "min_area"])]
+    output_properties_only: bool,
+
+    /// Geometry of the features: "full" assembles it, "linestring" emits the rings of polygons as
+    /// lines and "none" writes features without geometry, which is faster and cannot fail on
+    /// broken rings. "none" is only for geojson and csv output.
+    #[arg(long, value_name = "MODE", value_parser = ["full", "linestring", "none"], default_value = "full")]
+    geometry: String,
+
+    /// Comma-separated columns of csv output, e.g. `name,ars,area_km2`. Tags can be selected as
+    /// "tag:<key>". Defaults to `id,name,admin_level,ars,geometry_wkt`.
+    #[arg(long, value_delimiter = ',')]
+    fields: Vec<csv::Field>,
+
+    /// Write a single indented `FeatureCollection` instead of one feature per line, for reading
+    /// by humans. Only for geojson output.
+    #[arg(long, conflicts_with = "stream")]
+    pretty: bool,
+
+    /// Name the coordinate reference system in a `crs` member of the `FeatureCollection`, for
+    /// readers of the old `GeoJSON` specification. It was dropped from the current one, so strict
+    /// parsers may reject it. Only for geojson-collection output or with `--pretty`.
+    #[arg(long)]
+    emit_crs: bool,
+
     /// Query for relations with matching name. (Sub)string or pattern allowed.
     #[arg(short, long)]
     query: Option<String>,
 
+    /// Query for relations matching any of the names or patterns in this file, one per line.
+    #[arg(long, value_name = "PATH", conflicts_with = "query")]
+    query_file: Option<PathBuf>,
+
+    /// Tags to match the query and exclusion against. A match in any of them counts.
+    #[arg(long, value_delimiter = ',', default_value = "name")]
+    query_key: Vec<String>,
+
+    /// Only extract relations whose regional key `de:regionalschluessel` starts with this prefix,
+    /// e.g. "05" for all of North Rhine-Westphalia.
+    #[arg(long, value_name = "PREFIX")]
+    ars_prefix: Option<String>,
+
+    /// Only extract objects whose tags match this expression, e.g.
+    /// `boundary=administrative and admin_level>=6 and name~Berlin`. Supports `=`, `!=`, `~` for
+    /// patterns, `<`, `<=`, `>` and `>=` for numbers, `and`, `or` and parentheses. Quote values
+    /// with spaces. Applies in addition to the other filters.
+    #[arg(long = "where", value_name = "EXPR")]
+    where_expr: Option<filter::Expr>,
+
+    /// Skip relations with matching name. (Sub)string or pattern allowed.
+    #[arg(long)]
+    exclude: Option<String>,
+
+    /// Match the query case-sensitively. By default case is ignored.
+    #[arg(long)]
+    case_sensitive: bool,
+
+    /// How to interpret query and exclusion: "regex" requires a valid pattern, "literal" matches
+    /// the plain substring and "auto" uses a pattern if valid, else the substring.
+    #[arg(long, value_name = "MODE", default_value = "auto")]
+    query_mode: filter::QueryMode,
+
+    /// Comma-separated kinds of objects to extract: "relation", "way" or "node". Relations are
+    /// filtered as boundaries, ways and nodes only need a name.
+    #[arg(
+        long = "type",
+        value_name = "TYPE",
+        value_delimiter = ',',
+        default_value = "relation"
+    )]
+    types: Vec<filter::ObjectType>,
+
+    /// How to handle `admin_level` values which are no plain number, like "8;9": "first" uses
+    /// the first number in them, "raw" emits the value as string and "fail" skips the relation.
+    #[arg(long, value_name = "MODE", default_value = "first")]
+    admin_level_fallback: geom::AdminLevelFallback,
+
+    /// Order features by "id", "name" or `admin_level`. All features are converted before the
+    /// first is written. By default they are ordered by type of object and id.
+    #[arg(long, value_name = "KEY", conflicts_with = "stream")]
+    sort_by: Option<geom::SortBy>,
+
+    /// Comma-separated list of admin levels to extract.
+    #[arg(long, value_delimiter = ',', default_values_t = filter::DEFAULT_ADMIN_LEVELS)]
+    admin_levels: Vec<u8>,
+
+    /// Range of admin levels to extract instead of listing them, e.g. "2..=8" or "2..9".
+    #[arg(long, value_name = "RANGE", conflicts_with = "admin_levels")]
+    admin_level_range: Option<filter::AdminLevelRange>,
+
+    /// Tag identifying relations, emitted as `ars` property. Relations without it are skipped.
+    /// Use "none" to extract relations regardless and omit the property.
+    #[arg(long, default_value = filter::DEFAULT_ID_TAG)]
+    id_tag: String,
+
+    /// What to take the feature id from: "relation" for the OSM id, "ars" for the `ars` property
+    /// (or the OSM id without it) or "name".
+    #[arg(long, value_name = "SOURCE", default_value = "relation")]
+    id_source: geom::IdSource,
+
+    /// Distance in degrees within which way endpoints are joined when assembling rings.
+    #[arg(long, default_value_t = 0.0)]
+    snap_tolerance: f64,
+
+    /// Only output features intersecting the bounding box "minlon,minlat,maxlon,maxlat".
+    #[arg(long, allow_hyphen_values = true)]
+    bbox: Option<geom::BBox>,
+
+    /// Only extract features whose centroid lies inside the polygons of this geojson file, or
+    /// any of whose positions do if they have no area.
+    #[arg(long, value_name = "PATH", value_parser = geom::Mask::from_file, conflicts_with = "stream")]
+    clip_mask: Option<geom::Mask>,
+
+    /// Skip features with an area below this many square kilometres, e.g. slivers from data
+    /// errors. Lines and points have no area.
+    #[arg(long, value_name = "KM2")]
+    min_area: Option<f64>,
+
+    /// Skip features with self-intersecting rings. By default they are reported and written.
+    #[arg(long)]
+    skip_invalid: bool,
+
+    /// Comma-separated languages to take the name from, most preferred first, e.g. "en,de" for
+    /// `name:en`, then `name:de`. "name" stands for the plain name, which is used if none match.
+    #[arg(long, value_delimiter = ',', value_name = "LANG")]
+    name_lang: Vec<String>,
+
+    /// Prepend `name:prefix` to the name instead of writing the prefixed name to the separate
+    /// `display_name` property.
+    #[arg(long)]
+    merge_name_prefix: bool,
+
+    /// Copy this tag into the feature properties if present. Can be given several times.
+    #[arg(long = "property", value_name = "KEY")]
+    properties: Vec<String>,
+
+    /// Copy all tags into the feature properties.
+    #[arg(long)]
+    all_tags: bool,
+
+    /// Comma-separated tags not to copy into the feature properties with `--property` or
+    /// `--all-tags`, e.g. "source,fixme,note".
+    #[arg(long, value_delimiter = ',', value_name = "KEYS")]
+    exclude_tags: Vec<String>,
+
+    /// Comma-separated tags to write as JSON numbers instead of strings, e.g. "population,area".
+    /// Values which are no number stay strings.
+    #[arg(long, value_delimiter = ',', value_name = "KEYS")]
+    numeric_tags: Vec<String>,
+
+    /// Emit a point inside the polygon as `center` property instead of the centroid, which can
+    /// lie outside of concave polygons.
+    #[arg(long)]
+    point_on_surface: bool,
+
+    /// Simplify rings with the Douglas-Peucker algorithm using this tolerance in degrees.
+    #[arg(long)]
+    simplify: Option<f64>,
+
+    /// Round output coordinates to this many decimal places.
+    #[arg(long)]
+    precision: Option<u8>,
+
+    /// Write coordinates as latitude, longitude. Note that this is not valid `GeoJSON`, which
+    /// requires longitude first.
+    #[arg(long)]
+    lat_lon: bool,
+
+    /// Stop after writing this many features.
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Number of threads for converting features. Defaults to the number of CPUs. If given, PBF
+    /// blocks are also decoded and filtered on these threads when extracting.
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Stream the input in several passes instead of loading all relations into memory.
+    /// Features are written in the order their geometry completes. Only for geojson output.
+    #[arg(long)]
+    stream: bool,
+
+    /// Log and skip corrupt PBF blocks instead of failing, and use the objects read before the
+    /// end of a truncated file. Objects in skipped blocks are missing, so relations may lack
+    /// members. Not with `--stream`.
+    #[arg(long, conflicts_with = "stream")]
+    skip_errors: bool,
+
+    /// Log progress while reading input and converting features.
+    #[arg(long)]
+    progress: bool,
+
+    /// Size in bytes of the buffer in front of the output. Larger buffers save system calls when
+    /// writing large outputs.
+    #[arg(long, value_name = "BYTES")]
+    buffer_size: Option<usize>,
+
+    /// Abort on the first relation which cannot be converted instead of skipping it.
+    #[arg(long)]
+    strict: bool,
+
+    /// Write the outer ways of relations whose boundary cannot be closed as lines, marked with the
+    /// property "incomplete", instead of skipping them.
+    #[arg(long)]
+    include_incomplete: bool,
+
+    /// Log more details. Repeat for even more.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log less. Repeat to only log errors or nothing at all.
+    #[arg(long, action = clap::ArgAction::Count)]
+    quiet: u8,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+impl Cli {
+    /// Parse the command line. Input files are required unless only completions are requested.
+    fn parse_args() -> Self {
+        let cli = Self::parse();
+
+        if cli.in_file.is_empty() && !matches!(cli.command, Some(Commands::Completions { .. })) {
+            Self::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "the following required arguments were not provided:\n  --in-file <IN_FILE>...",
+                )
+                .exit();
+        }
+
+        let needs_geometry = [
+            ("--bbox", cli.bbox.is_some()),
+            ("--clip-mask", cli.clip_mask.is_some()),
+            ("--min-area", cli.min_area.is_some()),
+        ];
+        if let Some((arg, _)) = needs_geometry.iter().find(|(_, given)| *given) {
+            if cli.geometry == "none" {
+                Self::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!("the argument '--geometry none' cannot be used with '{arg}'"),
+                    )
+                    .exit();
+            }
+        }
+
+        cli
+    }
+
+    /// The log level, starting from `Info` and adjusted by `--verbose` and `--quiet`.
+    fn log_level(&self) -> LevelFilter {
+        const LEVELS: [LevelFilter; 6] = [
+            LevelFilter::Off,
+            LevelFilter::Error,
+            LevelFilter::Warn,
+            LevelFilter::Info,
+            LevelFilter::Debug,
+            LevelFilter::Trace,
+        ];
+
+        let level = (3 + usize::from(self.verbose))
+            .saturating_sub(usize::from(self.quiet))
+            .min(LEVELS.len() - 1);
+        LEVELS[level]
+    }
+
+    /// The configured `--id-tag`, if not disabled.
+    fn id_tag(&self) -> Option<String> {
+        (self.id_tag != "none").then(|| self.id_tag.clone())
+    }
+
+    /// Filter for objects matching `--query` or `--query-file`, `--ars-prefix` and `--where` and
+    /// not matching `--exclude`, if given.
+    fn matches_query(&self) -> Result<impl Fn(&OsmObj) -> bool> {
+        let (keys, case_sensitive, mode) = (&self.query_key, self.case_sensitive, self.query_mode);
+        let queries = match &self.query_file {
+            Some(path) => {
+                let queries = std::fs::read_to_string(path)
+                    .with_context(|| format!("cannot read '{}'", path.display()))?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                if queries.is_empty() {
+                    bail!("'{}' contains no queries", path.display());
+                }
+                Some(queries)
+            }
+            None => self.query.clone().map(|query| vec![query]),
+        };
+        let query_filter = queries
+            .map(|queries| filter::by_queries(&queries, keys, case_sensitive, mode))
+            .transpose()?;
+        let exclude_filter = self
+            .exclude
+            .as_ref()
+            .map(|pattern| filter::excluding(pattern, keys, case_sensitive, mode))
+            .transpose()?;
+        let ars_filter = self.ars_prefix.clone().map(filter::by_ars_prefix);
+        let where_expr = self.where_expr.clone();
+
+        Ok(move |obj: &OsmObj| {
+            query_filter.as_ref().is_none_or(|f| f(obj))
+                && exclude_filter.as_ref().is_none_or(|f| f(obj))
+                && ars_filter.as_ref().is_none_or(|f| f(obj))
+                && where_expr.as_ref().is_none_or(|expr| expr.matches(obj))
+        })
+    }
+
+    /// The admin levels to extract, from the list or the range.
+    fn admin_levels(&self) -> Vec<u8> {
+        self.admin_level_range.as_ref().map_or_else(
+            || self.admin_levels.clone(),
+            filter::AdminLevelRange::levels,
+        )
+    }
+
+    /// Whether to convert objects without their geometry.
+    fn skip_geometry(&self) -> bool {
+        self.output_properties_only || self.geometry == "none"
+    }
+
+    /// Load the objects in the input files matching `pred` and their dependencies.
+    fn load(&self, pred: impl FnMut(&OsmObj) -> bool) -> Result<util::Objects> {
+        let pred = util::with_progress(pred, self.progress);
+        util::load_relations(&self.in_file, pred, self.skip_errors)
+    }
+
+    /// Whether to compress the output, as requested or implied by the output file name.
+    fn gzip(&self) -> bool {
+        self.gzip
+            || self
+                .out_file
+                .as_ref()
+                .is_some_and(|f| f.extension().is_some_and(|ext| ext == "gz"))
+    }
+
+    /// The output format, as requested or inferred from the output file name.
+    fn format(&self) -> &str {
+        if let Some(format) = &self.format {
+            return format;
+        }
+        self.out_file
+            .as_deref()
+            .and_then(format_of)
+            .unwrap_or("geojson")
+    }
+
+    /// The conversion settings selected on the command line.
+    fn options(&self) -> geom::Options {
+        geom::Options {
+            snap_tolerance: self.snap_tolerance,
+            bbox: self.bbox,
+            clip_mask: self.clip_mask.clone(),
+            min_area: self.min_area,
+            lat_lon: self.lat_lon,
+            precision: self.precision,
+            limit: self.limit,
+            id_tag: self.id_tag(),
+            id_source: self.id_source,
+            skip_invalid: self.skip_invalid,
+            point_on_surface: self.point_on_surface,
+            simplify: self.simplify,
+            progress: self.progress,
+            buffer_size: self.buffer_size,
+            strict: self.strict,
+            include_incomplete: self.include_incomplete,
+            admin_level_fallback: self.admin_level_fallback,
+            sort_by: self.sort_by,
+            name_langs: self.name_lang.clone(),
+            merge_name_prefix: self.merge_name_prefix,
+            pretty: self.pretty,
+            emit_crs: self.emit_crs,
+            // Tags selected as csv fields need to be copied into the properties.
+            properties: self
+                .properties
+                .iter()
+                .cloned()
+                .chain(self.fields.iter().filter_map(|field| match field {
+                    csv::Field::Tag(key) => Some(key.clone()),
+                    _ => None,
+                }))
+                .collect(),
+            all_tags: self.all_tags,
+            exclude_tags: self.exclude_tags.clone(),
+            numeric_tags: self.numeric_tags.clone(),
+            properties_only: self.skip_geometry(),
+            as_lines: self.geometry == "linestring",
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Output statistics about the PBF file
+    #[command(override_usage = "osmtools --in-file <IN_FILE>... stats [OPTIONS]")]
     Stats {
         /// Show stats for all relations, using minimal filters.
         #[arg(short, long)]
         all: bool,
+
+        /// Output format.
+        #[arg(short, long, value_parser=["text", "json"], default_value = "text")]
+        format: String,
+
+        /// Only list the N most frequent values in each section of the text output.
+        #[arg(long, value_name = "N")]
+        top: Option<usize>,
+
+        /// Count while reading the input once, without loading the members of relations. Needs
+        /// far less memory, but referenced nodes and ways and failed conversions are unknown.
+        #[arg(long)]
+        stream: bool,
+    },
+    /// List the tag keys of relations with their number of occurrences
+    #[command(override_usage = "osmtools --in-file <IN_FILE>... tags [OPTIONS]")]
+    Tags {
+        /// Count the tags of all objects instead of only relations.
+        #[arg(short, long)]
+        all: bool,
     },
+    /// List relations dropped by the admin level and tag filters with the reason
+    #[command(override_usage = "osmtools --in-file <IN_FILE>... report")]
+    Report,
+    /// Convert the relations which would be extracted without writing them, and list those
+    /// failing with the error. Exits with an error if any fail
+    #[command(override_usage = "osmtools [OPTIONS] --in-file <IN_FILE>... validate")]
+    Validate,
+    /// Print the number of relations which would be extracted, without converting them
+    #[command(override_usage = "osmtools [OPTIONS] --in-file <IN_FILE>... count")]
+    Count,
+    /// Print a completion script for the given shell
+    Completions { shell: Shell },
 }
 
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let cli = Cli::parse_args();
+
+    if let Some(Commands::Completions { shell }) = cli.command {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut stdout());
+        return Ok(());
+    }
 
-    SimpleLogger::new()
-        .with_level(log::LevelFilter::Info)
-        .init()?;
+    SimpleLogger::new().with_level(cli.log_level()).init()?;
+
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
 
     info!("Unpacking relations from {:?}", cli.in_file);
 
-    let out: Box<dyn io::Write> = if let Some(f) = cli.out_file {
-        let f = std::fs::File::create(f)?;
+    // Reject unsupported options before the output file is truncated.
+    check_output(&cli)?;
+    let mut out = output(&cli)?;
+
+    let by_target = filter::by_target(&cli.admin_levels(), cli.id_tag().as_deref());
+    let by_types = filter::by_types(&cli.types, &cli.admin_levels(), cli.id_tag().as_deref());
+    let matches_query = cli.matches_query()?;
+
+    match &cli.command {
+        Some(Commands::Stats {
+            all,
+            format,
+            top,
+            stream,
+        }) => {
+            info!("Getting stats");
+            let filter = |obj: &OsmObj| -> bool {
+                let selected = if *all {
+                    filter::all(obj)
+                } else {
+                    by_target(obj)
+                };
+                selected && matches_query(obj)
+            };
+            let mut totals = stats::Totals::default();
+            let counting = |obj: &OsmObj| {
+                totals.add(obj);
+                filter(obj)
+            };
+            let scan = util::with_progress(counting, cli.progress);
+            let counts = if *stream {
+                stats::Counts::scan(&cli.in_file, scan, &by_target)?
+            } else {
+                let relations = util::load_relations(&cli.in_file, scan, cli.skip_errors)?;
+                stats::Counts::collect(&relations, filter, &by_target, &cli.options())
+            };
+
+            match format.as_str() {
+                "json" => stats::write_json(&counts, totals, out)?,
+                "text" => stats::write(&counts, totals, *top, out)?,
+                _ => unreachable!(),
+            }
+        }
+        Some(Commands::Tags { all }) => {
+            info!("Listing tag keys");
+            let filter = |obj: &OsmObj| (*all || filter::all(obj)) && matches_query(obj);
+            stats::write_tags(&cli.in_file, util::with_progress(filter, cli.progress), out)?;
+        }
+        Some(Commands::Report) => {
+            info!("Reporting dropped relations");
+            let filter = |obj: &OsmObj| filter::all(obj) && matches_query(obj);
+            let reject = filter::rejection(&cli.admin_levels(), cli.id_tag().as_deref());
+            stats::write_rejected(
+                &cli.in_file,
+                util::with_progress(filter, cli.progress),
+                reject,
+                out,
+            )?;
+        }
+        Some(Commands::Validate) => {
+            info!("Validating geometries");
+            let filter = |obj: &OsmObj| by_types(obj) && matches_query(obj);
+            let objs = cli.load(filter)?;
+            // Self-intersecting and unclosed rings count as invalid, too.
+            let options = geom::Options {
+                skip_invalid: true,
+                include_incomplete: false,
+                properties_only: false,
+                ..cli.options()
+            };
+            let failed = stats::write_invalid(&objs, filter, &options, out)?;
+            if failed > 0 {
+                bail!("{failed} objects have invalid geometry");
+            }
+        }
+        Some(Commands::Count) => {
+            let filter = |obj: &OsmObj| by_types(obj) && matches_query(obj);
+            let count = stats::count(&cli.in_file, util::with_progress(filter, cli.progress))?;
+            writeln!(out, "{count}")?;
+        }
+        Some(Commands::Completions { .. }) => unreachable!(),
+        None => {
+            info!("Extracting localities");
+            let filter = |obj: &OsmObj| -> bool { by_types(obj) && matches_query(obj) };
+            let summary = extract(&cli, filter, out)?;
+            info!("{summary}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The output format implied by the extension of `path`, ignoring a trailing ".gz".
+fn format_of(path: &Path) -> Option<&'static str> {
+    let path = if path.extension().is_some_and(|ext| ext == "gz") {
+        Path::new(path.file_stem()?)
+    } else {
+        path
+    };
+
+    match path.extension()?.to_str()? {
+        "geojson" | "json" => Some("geojson"),
+        "topojson" => Some("topojson"),
+        "csv" => Some("csv"),
+        "wkt" => Some("wkt"),
+        "kml" => Some("kml"),
+        "gpkg" => Some("gpkg"),
+        _ => None,
+    }
+}
+
+/// Open the output selected in `cli`, compressed if requested.
+fn output(cli: &Cli) -> Result<Box<dyn io::Write>> {
+    let out: Box<dyn io::Write> = if let Some(f) = &cli.out_file {
+        let f = if cli.append {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(f)?
+        } else {
+            std::fs::File::create(f)?
+        };
         Box::new(f)
     } else {
         Box::new(stdout())
     };
 
-    if let Some(Commands::Stats { all }) = cli.command {
-        if cli.query.is_some() {
-            // todo implement --query for stats
-            bail!("Sorry, '--query' is not implemented for stats yet.");
-        }
-        info!("Getting stats");
-        stats::write(
-            &util::load_relations(
-                cli.in_file,
-                if all { filter::all } else { filter::by_target },
-            )?,
-            out,
-        )?;
+    Ok(if cli.gzip() {
+        // Buffer beneath the encoder as well, so compressed chunks are written in batches.
+        Box::new(GzEncoder::new(
+            cli.options().buffer(out),
+            Compression::default(),
+        ))
     } else {
-        info!("Extracting localities");
+        out
+    })
+}
 
-        let filter = |obj: &OsmObj| -> bool {
-            let query_filter = cli.query.as_ref().map(|query| filter::by_query(query));
-            filter::by_target(obj) && query_filter.as_ref().map_or(true, |f| f(obj))
-        };
+/// Reject options which are not implemented for the output format selected in `cli`.
+fn check_output(cli: &Cli) -> Result<()> {
+    if cli.stream && cli.format() != "geojson" {
+        bail!("Sorry, '--stream' is only implemented for geojson output.");
+    }
 
-        match cli.format.as_deref() {
-            Some("raw") => {
-                let objs = util::load_relations(cli.in_file, &filter)?;
+    if cli.stream && cli.types != [filter::ObjectType::Relation] {
+        bail!("Sorry, '--stream' is only implemented for relations.");
+    }
 
-                // Use a buffered writer to amortize flushes.
-                let mut buffer = BufWriter::new(out);
+    if cli.out_dir.is_some() && cli.format() != "geojson" {
+        bail!("Sorry, '--out-dir' is only implemented for geojson output.");
+    }
 
-                for relation in objs.values().filter(|obj| filter(obj)) {
-                    writeln!(buffer, "{}", serde_json::to_string(&relation)?)?;
-                }
-            }
-            Some("geojson") | None => {
-                geom::write(&util::load_relations(cli.in_file, &filter)?, out)?;
-            }
-            _ => unreachable!(),
-        }
+    if cli.format() == "raw" && cli.bbox.is_some() {
+        bail!("Sorry, '--bbox' is not implemented for raw output yet.");
+    }
+
+    if cli.format() == "raw" && cli.clip_mask.is_some() {
+        bail!("Sorry, '--clip-mask' is not implemented for raw output.");
+    }
+
+    if cli.format() == "raw" && cli.min_area.is_some() {
+        bail!("Sorry, '--min-area' is not implemented for raw output.");
+    }
+
+    if cli.format() == "raw" && cli.lat_lon {
+        bail!("Sorry, '--lat-lon' is not implemented for raw output.");
+    }
+
+    if cli.format() == "gpkg" && (cli.out_file.is_none() || cli.gzip()) {
+        bail!("Sorry, gpkg output can only be written uncompressed to '--out-file'.");
+    }
+
+    if cli.pretty && !matches!(cli.format(), "geojson" | "geojson-collection") {
+        bail!("Sorry, '--pretty' is only implemented for geojson output.");
+    }
+
+    if cli.emit_crs
+        && !(cli.format() == "geojson-collection" || cli.format() == "geojson" && cli.pretty)
+    {
+        bail!("Sorry, '--emit-crs' is only implemented for geojson-collection or pretty output.");
+    }
+
+    if cli.sort_by.is_some() && cli.format() == "raw" {
+        bail!("Sorry, '--sort-by' is not implemented for raw output.");
+    }
+
+    if !cli.fields.is_empty() && cli.format() != "csv" {
+        bail!("Sorry, '--fields' is only implemented for csv output.");
+    }
+
+    if cli.geometry == "none"
+        && !matches!(
+            cli.format(),
+            "geojson" | "geojson-seq" | "geojson-collection" | "csv"
+        )
+    {
+        bail!("Sorry, '--geometry none' is only implemented for geojson and csv output.");
+    }
+
+    if cli.output_properties_only && !matches!(cli.format(), "geojson" | "csv") {
+        bail!("Sorry, '--output-properties-only' is only implemented for geojson and csv output.");
+    }
+
+    if cli.append
+        && !matches!(
+            cli.format(),
+            "geojson" | "geojson-seq" | "csv" | "wkt" | "raw"
+        )
+    {
+        bail!("Sorry, '--append' is only implemented for line-based output.");
+    }
+
+    if cli.raw_tags_only && cli.format() != "raw" {
+        bail!("Sorry, '--raw-tags-only' is only implemented for raw output.");
     }
 
     Ok(())
 }
+
+/// Write the relations matching `filter` in the output format selected in `cli`.
+fn extract(
+    cli: &Cli,
+    filter: impl Fn(&OsmObj) -> bool + Copy + Sync,
+    out: Box<dyn io::Write>,
+) -> Result<geom::Summary> {
+    let options = cli.options();
+
+    if cli.stream {
+        return geom::write_streaming(&cli.in_file, filter, out, &options);
+    }
+
+    // Reject relations far from the bounding box before their members are loaded.
+    let by_bbox = cli
+        .bbox
+        .map(|bbox| util::by_bbox(&cli.in_file, bbox))
+        .transpose()?;
+    let by_bbox = by_bbox.as_ref();
+    let filter = move |obj: &OsmObj| filter(obj) && by_bbox.is_none_or(|f| f(obj));
+
+    let objs = if cli.threads.is_some() {
+        util::load_relations_par(
+            &cli.in_file,
+            util::with_progress_sync(filter, cli.progress),
+            cli.skip_errors,
+        )?
+    } else {
+        cli.load(filter)?
+    };
+
+    if let Some(dir) = &cli.out_dir {
+        return geom::write_files(&objs, filter, dir, &options);
+    }
+
+    let summary = match cli.format() {
+        "raw" => {
+            // Use a buffered writer to amortize flushes.
+            let mut buffer = options.buffer(out);
+            let mut summary = geom::Summary::default();
+
+            for relation in util::matching(&objs, filter)
+                .into_iter()
+                .take(cli.limit.unwrap_or(usize::MAX))
+            {
+                let line = if cli.raw_tags_only {
+                    serde_json::json!({
+                        "id": relation.id().inner_id(),
+                        "type": filter::ObjectType::of(relation).to_string(),
+                        "tags": relation.tags(),
+                    })
+                    .to_string()
+                } else {
+                    serde_json::to_string(&relation)?
+                };
+                writeln!(buffer, "{line}")?;
+                summary.written += 1;
+            }
+
+            summary
+        }
+        "geojson" if cli.output_properties_only => {
+            geom::write_properties(&objs, filter, out, &options)?
+        }
+        "geojson" if cli.pretty => geom::write_collection(&objs, filter, out, &options)?,
+        "geojson" => geom::write(&objs, filter, out, &options)?,
+        "geojson-seq" => geom::write_seq(&objs, filter, out, &options)?,
+        "geojson-collection" => geom::write_collection(&objs, filter, out, &options)?,
+        "topojson" => topojson::write(&objs, filter, out, &options)?,
+        "csv" => {
+            let fields = match (cli.fields.is_empty(), cli.skip_geometry()) {
+                // There is no geometry to write.
+                (true, true) => &csv::DEFAULT_FIELDS[..csv::DEFAULT_FIELDS.len() - 1],
+                (true, false) => &csv::DEFAULT_FIELDS[..],
+                (false, _) => &cli.fields,
+            };
+            // Appended rows continue the table in the file, unless there is none yet.
+            let header = !cli.append
+                || cli
+                    .out_file
+                    .as_ref()
+                    .is_none_or(|f| std::fs::metadata(f).is_ok_and(|m| m.len() == 0));
+            csv::write(&objs, filter, fields, header, out, &options)?
+        }
+        "wkt" => wkt::write(&objs, filter, out, &options)?,
+        "kml" => kml::write(&objs, filter, out, &options)?,
+        "gpkg" => {
+            // The database is written to the same file.
+            drop(out);
+            let path = cli.out_file.as_deref().expect("checked above");
+            gpkg::write(&objs, filter, path, &options)?
+        }
+        _ => unreachable!(),
+    };
+
+    Ok(summary)
+}
@@ -1,76 +1,580 @@
 use anyhow::Result;
 use itertools::Itertools;
-use osmpbfreader::{OsmId, OsmObj, Tags};
-use std::collections::{BTreeMap, HashMap};
+use log::debug;
+use osmpbfreader::{OsmObj, Tags};
+use rayon::prelude::*;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
 use std::io;
+use std::path::PathBuf;
 
-use crate::filter;
+use crate::{
+    filter::Rejection,
+    geom,
+    util::{self, Objects},
+};
 
-pub fn write(relations: &BTreeMap<OsmId, OsmObj>, mut out: impl io::Write) -> Result<()> {
-    let mut count_relations = 0;
-    let mut count_admin = HashMap::<&str, usize>::new();
-    let mut count_boundaries = HashMap::<&str, usize>::new();
-    let mut count_tags = HashMap::<&str, usize>::new();
-    let mut count_types = HashMap::<&str, usize>::new();
+/// Numbers of nodes and ways read from the input. Objects contained in several files are counted
+/// for each of them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Totals {
+    pub nodes: usize,
+    pub ways: usize,
+}
+
+impl Totals {
+    /// Count `obj` if it is a node or way.
+    pub fn add(&mut self, obj: &OsmObj) {
+        match obj {
+            OsmObj::Node(_) => self.nodes += 1,
+            OsmObj::Way(_) => self.ways += 1,
+            OsmObj::Relation(_) => {}
+        }
+    }
+}
+
+/// Counts collected over the matching relations, to be written with `stats::write` or
+/// `stats::write_json`.
+#[derive(Default)]
+pub struct Counts {
+    relations: usize,
+    /// Nodes and ways loaded as dependencies of the relations. Unknown, like conversion
+    /// failures, if the input was only scanned.
+    referenced: Option<Totals>,
+    admin: HashMap<String, usize>,
+    boundaries: HashMap<String, usize>,
+    tags: HashMap<String, usize>,
+    types: HashMap<String, usize>,
+    levels: HashMap<String, Level>,
+    /// Members by role, with `EMPTY_ROLE` for those without.
+    roles: HashMap<String, usize>,
+    /// Relations by their number of members.
+    members: HashMap<usize, usize>,
+}
 
-    for obj in relations.values().filter(|obj| filter::all(obj)) {
-        count_relations += 1;
+/// Stands for an empty member role in the counts.
+const EMPTY_ROLE: &str = "(empty)";
+
+/// Counts of the relations with one admin level.
+#[derive(Default)]
+struct Level {
+    relations: usize,
+    /// Relations selected by the target filter.
+    selected: usize,
+    /// Selected relations which cannot be converted to features.
+    failed: usize,
+}
+
+impl Counts {
+    /// Count the objects in `relations` matching `pred`. For each admin level the relations
+    /// selected by `target` and those of them failing conversion with `options` are counted, too.
+    #[must_use]
+    pub fn collect(
+        relations: &Objects,
+        pred: impl Fn(&OsmObj) -> bool,
+        target: impl Fn(&OsmObj) -> bool,
+        options: &geom::Options,
+    ) -> Self {
+        let positions = geom::node_positions(relations);
+        let position = |id| positions.get(&id).copied();
+
+        let mut referenced = Totals::default();
+        for obj in relations.values() {
+            referenced.add(obj);
+        }
+        let mut counts = Self {
+            referenced: Some(referenced),
+            ..Self::default()
+        };
+
+        for obj in relations.values().filter(|obj| pred(obj)) {
+            counts.add(obj, target(obj), || {
+                let result = geom::convert(obj, relations, &position, options);
+                if let Err(e) = &result {
+                    debug!("{:?}: {e:#}", obj.id());
+                }
+                result.is_err()
+            });
+        }
+
+        counts
+    }
+
+    /// Count the objects in the files at `paths` matching `pred` like `collect`, but while
+    /// reading the files once. Dependencies are neither loaded nor converted, so this needs
+    /// little memory even for huge files. Objects contained in several files are counted for
+    /// each of them.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a file cannot be read.
+    pub fn scan(
+        paths: &[PathBuf],
+        mut pred: impl FnMut(&OsmObj) -> bool,
+        target: impl Fn(&OsmObj) -> bool,
+    ) -> Result<Self> {
+        let mut counts = Self::default();
+
+        for path in paths {
+            util::for_each_object(path, |obj| {
+                if pred(&obj) {
+                    counts.add(&obj, target(&obj), || false);
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(counts)
+    }
+
+    /// Count `obj`, which is `selected` by the target filter. `failed` tells whether it fails
+    /// conversion and is only called for selected objects with an admin level.
+    fn add(&mut self, obj: &OsmObj, selected: bool, failed: impl FnOnce() -> bool) {
+        self.relations += 1;
 
         let tags = obj.tags();
 
-        add_count(tags, &mut count_admin, "admin_level");
-        add_count(tags, &mut count_boundaries, "boundary");
-        add_count(tags, &mut count_types, "type");
+        if let Some(admin_level) = tags.get("admin_level") {
+            let level = match self.levels.get_mut(admin_level.as_str()) {
+                Some(level) => level,
+                None => self.levels.entry(admin_level.to_string()).or_default(),
+            };
+            level.relations += 1;
+            if selected {
+                level.selected += 1;
+                if failed() {
+                    level.failed += 1;
+                }
+            }
+        }
+
+        add_count(tags, &mut self.admin, "admin_level");
+        add_count(tags, &mut self.boundaries, "boundary");
+        add_count(tags, &mut self.types, "type");
 
         for tag in tags
             .keys()
             .filter(|tag| !matches!(tag.as_str(), "boundary" | "type"))
         {
-            *count_tags.entry(tag).or_default() += 1;
+            increment(&mut self.tags, tag);
+        }
+
+        if let OsmObj::Relation(relation) = obj {
+            for child in &relation.refs {
+                let role = match child.role.as_str() {
+                    "" => EMPTY_ROLE,
+                    role => role,
+                };
+                increment(&mut self.roles, role);
+            }
+            *self.members.entry(relation.refs.len()).or_default() += 1;
         }
     }
+}
+
+/// Write the statistics in `counts`. `totals` are the numbers of nodes and ways in the input,
+/// reported along with those referenced by the loaded relations if known. With `top` only that
+/// many most frequent values are listed per section.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails.
+pub fn write(
+    counts: &Counts,
+    totals: Totals,
+    top: Option<usize>,
+    mut out: impl io::Write,
+) -> Result<()> {
+    let referenced = |count: fn(&Totals) -> usize| {
+        counts
+            .referenced
+            .as_ref()
+            .map(|referenced| format!(" ({} referenced)", count(referenced)))
+            .unwrap_or_default()
+    };
 
     write!(
         out,
         "\
 Stats
 --------------------
-Total number of relations: {count_relations}
+Total number of nodes: {}{}
+Total number of ways: {}{}
+Total number of relations: {}
 
 Administrative levels (count):
 
+{}
+Administrative levels (relations, selected, failed conversion):
+
 {}
 Boundary values (count):
 
 {}
 Type values (count):
 
+{}
+Member roles (count):
+
+{}
+Members per relation (count):
+
 {}
 Other tags ({}):
 
 {}",
-        to_string(&count_admin),
-        to_string(&count_boundaries),
-        to_string(&count_types),
-        count_tags.len(),
-        to_string(&count_tags),
+        totals.nodes,
+        referenced(|r| r.nodes),
+        totals.ways,
+        referenced(|r| r.ways),
+        counts.relations,
+        to_string(&counts.admin, top),
+        levels_to_string(&counts.levels, counts.referenced.is_some()),
+        to_string(&counts.boundaries, top),
+        to_string(&counts.types, top),
+        to_string(&counts.roles, top),
+        to_string(&counts.members, top),
+        counts.tags.len(),
+        to_string(&counts.tags, top),
     )?;
 
     Ok(())
 }
 
-fn add_count<'a>(tags: &'a Tags, counts: &mut HashMap<&'a str, usize>, key: &str) {
+/// Write statistics like `stats::write`, but as a single JSON object with sorted keys. Unknown
+/// counts are null.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails.
+pub fn write_json(counts: &Counts, totals: Totals, mut out: impl io::Write) -> Result<()> {
+    // Without the dependencies conversion failures are unknown.
+    let converted = counts.referenced.is_some();
+    let levels = counts
+        .levels
+        .iter()
+        .map(|(value, level)| {
+            let level = json!({
+                "relations": level.relations,
+                "selected": level.selected,
+                "failed": converted.then_some(level.failed),
+            });
+            (value.as_str(), level)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let stats = json!({
+        "total_nodes": totals.nodes,
+        "total_ways": totals.ways,
+        "total_relations": counts.relations,
+        "referenced_nodes": counts.referenced.map(|r| r.nodes),
+        "referenced_ways": counts.referenced.map(|r| r.ways),
+        "admin_levels": sorted(&counts.admin),
+        "admin_level_breakdown": levels,
+        "boundary_values": sorted(&counts.boundaries),
+        "type_values": sorted(&counts.types),
+        "member_roles": sorted(&counts.roles),
+        "member_counts": counts
+            .members
+            .iter()
+            .map(|(members, count)| (members.to_string(), *count))
+            .collect::<BTreeMap<_, _>>(),
+        "other_tags": sorted(&counts.tags),
+    });
+
+    writeln!(out, "{stats}")?;
+
+    Ok(())
+}
+
+/// Count the objects in the files at `paths` matching `pred`, without loading their
+/// dependencies. Objects contained in several files are counted once.
+///
+/// # Errors
+///
+/// Fails if a file cannot be read.
+pub fn count(paths: &[PathBuf], mut pred: impl FnMut(&OsmObj) -> bool) -> Result<usize> {
+    let mut ids = HashSet::new();
+
+    for path in paths {
+        util::for_each_object(path, |obj| {
+            if pred(&obj) {
+                ids.insert(obj.id());
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(ids.len())
+}
+
+/// Write each distinct tag key of the objects in the files at `paths` matching `pred` with
+/// its number of occurrences, most frequent first. Only the objects themselves are scanned, not
+/// their dependencies.
+///
+/// # Errors
+///
+/// Fails if a file cannot be read or writing to `out` fails.
+pub fn write_tags(
+    paths: &[PathBuf],
+    mut pred: impl FnMut(&OsmObj) -> bool,
+    mut out: impl io::Write,
+) -> Result<()> {
+    let mut counts = HashMap::<String, usize>::new();
+
+    for path in paths {
+        util::for_each_object(path, |obj| {
+            if pred(&obj) {
+                for key in obj.tags().keys() {
+                    *counts.entry(key.to_string()).or_default() += 1;
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    write!(out, "{}", to_string(&counts, None))?;
+
+    Ok(())
+}
+
+/// Write id, name and the reason for dropping each relation in the files at `paths` which
+/// matches `pred` but is rejected by `reject`, separated by tabs.
+///
+/// # Errors
+///
+/// Fails if a file cannot be read or writing to `out` fails.
+pub fn write_rejected(
+    paths: &[PathBuf],
+    mut pred: impl FnMut(&OsmObj) -> bool,
+    reject: impl Fn(&OsmObj) -> Option<Rejection>,
+    mut out: impl io::Write,
+) -> Result<()> {
+    for path in paths {
+        util::for_each_object(path, |obj| {
+            if !pred(&obj) {
+                return Ok(());
+            }
+
+            if let Some(reason) = reject(&obj) {
+                let name = obj.tags().get("name").map_or("", |name| name.as_str());
+                writeln!(out, "{}\t{name}\t{reason}", obj.id().inner_id())?;
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Convert each object in `relations` matching `pred` with `options` and write id, name and the
+/// root cause of the error for each which fails, separated by tabs and ordered by id, followed by
+/// the number of objects checked and failed. Returns the number of failures.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails.
+pub fn write_invalid(
+    relations: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    options: &geom::Options,
+    mut out: impl io::Write,
+) -> Result<usize> {
+    let positions = geom::node_positions(relations);
+    let position = |id| positions.get(&id).copied();
+    let matching = util::matching(relations, pred);
+
+    let errors = matching
+        .par_iter()
+        .filter_map(|obj| {
+            let e = geom::convert(obj, relations, &position, options).err()?;
+            Some((obj, e))
+        })
+        .collect::<Vec<_>>();
+
+    for (obj, e) in &errors {
+        let name = obj.tags().get("name").map_or("", |name| name.as_str());
+        writeln!(out, "{}\t{name}\t{}", obj.id().inner_id(), e.root_cause())?;
+    }
+    writeln!(
+        out,
+        "Checked {} objects, {} invalid",
+        matching.len(),
+        errors.len()
+    )?;
+
+    Ok(errors.len())
+}
+
+fn add_count(tags: &Tags, counts: &mut HashMap<String, usize>, key: &str) {
     if let Some(value) = tags.get(key) {
-        *counts.entry(value).or_default() += 1;
+        increment(counts, value);
+    }
+}
+
+/// Count `key` once more, allocating it only when it is new.
+fn increment(counts: &mut HashMap<String, usize>, key: &str) {
+    match counts.get_mut(key) {
+        Some(count) => *count += 1,
+        None => {
+            counts.insert(key.to_string(), 1);
+        }
     }
 }
 
-fn to_string(map: &HashMap<&str, usize>) -> String {
+fn sorted(map: &HashMap<String, usize>) -> BTreeMap<&str, usize> {
+    map.iter()
+        .map(|(value, count)| (value.as_str(), *count))
+        .collect()
+}
+
+/// List the counts for each admin level, ordered by level. Failed conversions are only listed
+/// if `converted`, else marked as unknown.
+fn levels_to_string(levels: &HashMap<String, Level>, converted: bool) -> String {
     let mut out = String::new();
 
-    for (value, count) in map.iter().sorted_by(|a, b| Ord::cmp(&b.1, &a.1)) {
-        out.push_str(&format!("{value} {count}\n"));
+    for (value, level) in levels
+        .iter()
+        .sorted_by_key(|(value, _)| (value.parse::<u8>().unwrap_or(u8::MAX), value.as_str()))
+    {
+        let failed = if converted {
+            level.failed.to_string()
+        } else {
+            "-".to_string()
+        };
+        let _ = writeln!(
+            out,
+            "{value} {} {} {failed}",
+            level.relations, level.selected
+        );
     }
 
     out
 }
+
+/// List values with their counts, most frequent first. With `top` the list is cut after that
+/// many values and ends with the number of values left out.
+fn to_string<K: Ord + std::fmt::Display>(map: &HashMap<K, usize>, top: Option<usize>) -> String {
+    let mut out = String::new();
+    let top = top.unwrap_or(usize::MAX);
+
+    // Break ties by value to keep the output stable.
+    for (value, count) in map
+        .iter()
+        .sorted_by(|a, b| Ord::cmp(&b.1, &a.1).then(Ord::cmp(&a.0, &b.0)))
+        .take(top)
+    {
+        let _ = writeln!(out, "{value} {count}");
+    }
+    if map.len() > top {
+        let _ = writeln!(out, "... and {} more", map.len() - top);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{levels_to_string, to_string, write_invalid, Counts, Level};
+    use crate::geom::test::{add_relation, add_tags};
+    use crate::util::Objects;
+
+    #[test]
+    fn to_string_top() {
+        let counts = HashMap::from([("name", 4), ("wikidata", 1), ("population", 1)]);
+
+        assert_eq!(
+            to_string(&counts, None),
+            "name 4\npopulation 1\nwikidata 1\n"
+        );
+        assert_eq!(to_string(&counts, Some(1)), "name 4\n... and 2 more\n");
+        assert_eq!(to_string(&counts, Some(3)), to_string(&counts, None));
+    }
+
+    #[test]
+    fn write_invalid_objects() {
+        use crate::geom;
+        use osmpbfreader::{Node, NodeId, OsmId, OsmObj, RelationId, Tags};
+
+        let mut objs = Objects::default();
+        let mut node = OsmObj::Node(Node {
+            id: NodeId(1),
+            tags: Tags::new(),
+            decimicro_lat: 0,
+            decimicro_lon: 0,
+        });
+        add_tags(&mut node, &[("name", "Platz")]);
+        objs.insert(OsmId::Node(NodeId(1)), node);
+        for (id, tags) in [
+            (2, &[("name", "Musterstadt"), ("admin_level", "8")][..]),
+            (3, &[("admin_level", "8")]),
+        ] {
+            add_relation(&mut objs, id, &[]);
+            add_tags(
+                objs.get_mut(&OsmId::Relation(RelationId(id))).unwrap(),
+                tags,
+            );
+        }
+
+        let mut out = vec![];
+        let failed = write_invalid(&objs, |_| true, &geom::Options::default(), &mut out).unwrap();
+        assert_eq!(failed, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2\tMusterstadt\trelation 2 has no outer ways\n\
+             3\t\t'name' is missing\n\
+             Checked 3 objects, 2 invalid\n"
+        );
+    }
+
+    #[test]
+    fn levels_without_conversion() {
+        let levels = HashMap::from([
+            (
+                "10".to_string(),
+                Level {
+                    relations: 1,
+                    selected: 0,
+                    failed: 0,
+                },
+            ),
+            (
+                "8".to_string(),
+                Level {
+                    relations: 3,
+                    selected: 2,
+                    failed: 1,
+                },
+            ),
+        ]);
+
+        assert_eq!(levels_to_string(&levels, true), "8 3 2 1\n10 1 0 0\n");
+        assert_eq!(levels_to_string(&levels, false), "8 3 2 -\n10 1 0 -\n");
+    }
+
+    #[test]
+    fn count_members() {
+        use osmpbfreader::{OsmId, WayId};
+
+        let relation = |roles: &[&str]| {
+            let members = roles
+                .iter()
+                .map(|&role| (OsmId::Way(WayId(1)), role))
+                .collect::<Vec<_>>();
+            add_relation(&mut Objects::default(), 1, &members)
+        };
+
+        let mut counts = Counts::default();
+        counts.add(&relation(&["outer", "outer", "inner"]), false, || false);
+        counts.add(&relation(&["outer", ""]), false, || false);
+
+        assert_eq!(
+            to_string(&counts.roles, None),
+            "outer 3\n(empty) 1\ninner 1\n"
+        );
+        assert_eq!(to_string(&counts.members, None), "2 1\n3 1\n");
+    }
+}
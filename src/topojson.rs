@@ -0,0 +1,293 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    io::{self, Write},
+};
+
+use anyhow::Result;
+use geojson::{Feature, Value};
+use osmpbfreader::OsmObj;
+use serde_json::json;
+
+use crate::{geom, util::Objects};
+
+/// Name of the geometry collection holding the features in the topology.
+const OBJECT_NAME: &str = "localities";
+
+/// Write the objects in `objs` matching `pred` as a single `TopoJSON` topology. Lines shared by
+/// several features, like the common border of neighbouring boundaries, are stored only once.
+///
+/// All features are held in memory until the arcs have been extracted.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails or, with `options.strict`, if an object cannot be converted.
+pub fn write(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    out: impl io::Write,
+    options: &geom::Options,
+) -> Result<geom::Summary> {
+    let mut summary = geom::Summary::default();
+    let features =
+        geom::features(objs, pred, options, &mut summary.skipped).collect::<Result<Vec<_>>>()?;
+    summary.written = features.len();
+
+    // Use a buffered writer to amortize flushes.
+    let mut buffer = options.buffer(out);
+    writeln!(buffer, "{}", to_topojson(&features))?;
+
+    Ok(summary)
+}
+
+/// Build a `TopoJSON` topology from `features`. Rings and lines are cut where they meet others and
+/// each resulting arc is stored once; features refer to arcs by index, the ones' complement of
+/// it if the arc is traversed in reverse. Coordinates are not quantized.
+#[must_use]
+pub fn to_topojson(features: &[Feature]) -> serde_json::Value {
+    let mut topology = Topology {
+        junctions: junctions(features.iter().filter_map(|f| f.geometry.as_ref())),
+        ..Topology::default()
+    };
+
+    let geometries = features
+        .iter()
+        .map(|feature| {
+            let mut object = match &feature.geometry {
+                Some(geometry) => topology.object(&geometry.value),
+                None => json!({ "type": null }),
+            };
+            if let Some(id) = &feature.id {
+                object["id"] = json!(id);
+            }
+            if let Some(properties) = &feature.properties {
+                object["properties"] = json!(properties);
+            }
+            object
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "type": "Topology",
+        "objects": {
+            OBJECT_NAME: {
+                "type": "GeometryCollection",
+                "geometries": geometries,
+            },
+        },
+        "arcs": topology.arcs,
+    })
+}
+
+/// Hashable identity of a position.
+type Key = (u64, u64);
+
+fn key(position: &[f64]) -> Key {
+    // Adding zero turns -0 into 0, which compare equal but differ in bits.
+    ((position[0] + 0.0).to_bits(), (position[1] + 0.0).to_bits())
+}
+
+#[derive(Default)]
+struct Topology {
+    /// Positions where lines have to be cut into arcs.
+    junctions: HashSet<Key>,
+    arcs: Vec<Vec<Vec<f64>>>,
+    index: HashMap<Vec<Key>, usize>,
+}
+
+impl Topology {
+    /// The `TopoJSON` geometry object for `geometry`.
+    fn object(&mut self, geometry: &Value) -> serde_json::Value {
+        match geometry {
+            Value::Point(p) => json!({ "type": "Point", "coordinates": p }),
+            Value::MultiPoint(ps) => json!({ "type": "MultiPoint", "coordinates": ps }),
+            Value::LineString(ps) => json!({ "type": "LineString", "arcs": self.cut(ps, false) }),
+            Value::MultiLineString(lines) => json!({
+                "type": "MultiLineString",
+                "arcs": lines.iter().map(|ps| self.cut(ps, false)).collect::<Vec<_>>(),
+            }),
+            Value::Polygon(rings) => json!({ "type": "Polygon", "arcs": self.rings(rings) }),
+            Value::MultiPolygon(polygons) => json!({
+                "type": "MultiPolygon",
+                "arcs": polygons.iter().map(|rings| self.rings(rings)).collect::<Vec<_>>(),
+            }),
+            Value::GeometryCollection(geometries) => json!({
+                "type": "GeometryCollection",
+                "geometries": geometries
+                    .iter()
+                    .map(|g| self.object(&g.value))
+                    .collect::<Vec<_>>(),
+            }),
+        }
+    }
+
+    fn rings(&mut self, rings: &[Vec<Vec<f64>>]) -> Vec<Vec<i64>> {
+        rings.iter().map(|ring| self.cut(ring, true)).collect()
+    }
+
+    /// Cut `line` at junctions and return the indices of its arcs. Closed rings are rotated to
+    /// start at a junction, or at their least position if they have none, so equal rings yield
+    /// equal arcs.
+    fn cut(&mut self, line: &[Vec<f64>], closed: bool) -> Vec<i64> {
+        let mut points = line.to_vec();
+        if closed && points.len() > 1 {
+            points.pop();
+            let start = points
+                .iter()
+                .position(|p| self.junctions.contains(&key(p)))
+                .or_else(|| (0..points.len()).min_by_key(|&i| key(&points[i])))
+                .unwrap_or_default();
+            points.rotate_left(start);
+            points.push(points[0].clone());
+        }
+
+        let mut arcs = vec![];
+        let mut start = 0;
+        for i in 1..points.len() {
+            if i == points.len() - 1 || self.junctions.contains(&key(&points[i])) {
+                arcs.push(self.arc(&points[start..=i]));
+                start = i;
+            }
+        }
+        arcs
+    }
+
+    /// The index of the arc along `points`, added if neither it nor its reverse is known yet.
+    fn arc(&mut self, points: &[Vec<f64>]) -> i64 {
+        let index = |i: usize| i64::try_from(i).expect("number of arcs fits into i64");
+
+        let keys = points.iter().map(|p| key(p)).collect::<Vec<_>>();
+        if let Some(&i) = self.index.get(&keys) {
+            return index(i);
+        }
+        let reversed = keys.iter().rev().copied().collect::<Vec<_>>();
+        if let Some(&i) = self.index.get(&reversed) {
+            return !index(i);
+        }
+
+        let i = self.arcs.len();
+        self.index.insert(keys, i);
+        self.arcs.push(points.to_vec());
+        index(i)
+    }
+}
+
+/// Find the positions where lines of `geometries` meet: those reached from different neighbours
+/// by different lines, and the ends of open lines.
+fn junctions<'a>(geometries: impl Iterator<Item = &'a geojson::Geometry>) -> HashSet<Key> {
+    let mut neighbours = HashMap::<Key, (Key, Key)>::new();
+    let mut junctions = HashSet::new();
+
+    let mut visit = |points: &[Vec<f64>], closed: bool| {
+        // Closed rings repeat their first position at the end.
+        let n = if closed {
+            points.len().saturating_sub(1)
+        } else {
+            points.len()
+        };
+
+        for i in 0..n {
+            let here = key(&points[i]);
+            let (previous, next) = if closed {
+                (key(&points[(i + n - 1) % n]), key(&points[(i + 1) % n]))
+            } else if i == 0 || i == n - 1 {
+                junctions.insert(here);
+                continue;
+            } else {
+                (key(&points[i - 1]), key(&points[i + 1]))
+            };
+
+            let pair = (previous.min(next), previous.max(next));
+            match neighbours.entry(here) {
+                Entry::Occupied(entry) if *entry.get() != pair => {
+                    junctions.insert(here);
+                }
+                Entry::Occupied(_) => {}
+                Entry::Vacant(entry) => {
+                    entry.insert(pair);
+                }
+            }
+        }
+    };
+
+    for geometry in geometries {
+        for_each_line(&geometry.value, &mut visit);
+    }
+
+    junctions
+}
+
+/// Call `f` for each line string and ring of `geometry`, telling whether it is a ring.
+fn for_each_line(geometry: &Value, f: &mut impl FnMut(&[Vec<f64>], bool)) {
+    match geometry {
+        Value::Point(_) | Value::MultiPoint(_) => {}
+        Value::LineString(ps) => f(ps, false),
+        Value::MultiLineString(lines) => lines.iter().for_each(|ps| f(ps, false)),
+        Value::Polygon(rings) => rings.iter().for_each(|ring| f(ring, true)),
+        Value::MultiPolygon(polygons) => polygons.iter().flatten().for_each(|ring| f(ring, true)),
+        Value::GeometryCollection(geometries) => {
+            for g in geometries {
+                for_each_line(&g.value, f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use geojson::{Feature, Geometry, Value};
+    use serde_json::json;
+
+    use super::to_topojson;
+
+    fn square(x: f64) -> Feature {
+        Feature::from(Geometry::new(Value::Polygon(vec![vec![
+            vec![x, 0.],
+            vec![x + 1., 0.],
+            vec![x + 1., 1.],
+            vec![x, 1.],
+            vec![x, 0.],
+        ]])))
+    }
+
+    #[test]
+    fn shared_arcs() {
+        let topology = to_topojson(&[square(0.), square(1.)]);
+
+        // The common edge is stored once and traversed in reverse by the second square.
+        assert_eq!(
+            topology["arcs"],
+            json!([
+                [[1., 0.], [1., 1.]],
+                [[1., 1.], [0., 1.], [0., 0.], [1., 0.]],
+                [[1., 0.], [2., 0.], [2., 1.], [1., 1.]],
+            ])
+        );
+        assert_eq!(
+            topology["objects"]["localities"]["geometries"],
+            json!([
+                { "type": "Polygon", "arcs": [[0, 1]] },
+                { "type": "Polygon", "arcs": [[2, -1]] },
+            ])
+        );
+    }
+
+    #[test]
+    fn equal_rings() {
+        let mut rotated = square(0.);
+        rotated.geometry = Some(Geometry::new(Value::Polygon(vec![vec![
+            vec![1., 1.],
+            vec![1., 0.],
+            vec![0., 0.],
+            vec![0., 1.],
+            vec![1., 1.],
+        ]])));
+
+        let topology = to_topojson(&[square(0.), rotated]);
+
+        assert_eq!(topology["arcs"].as_array().map(Vec::len), Some(1));
+        assert_eq!(
+            topology["objects"]["localities"]["geometries"][1]["arcs"],
+            json!([[-1]])
+        );
+    }
+}
@@ -1,14 +1,610 @@
-use anyhow::Result;
-use osmpbfreader::{OsmId, OsmObj, OsmPbfReader};
-use std::{collections::BTreeMap, path::PathBuf};
+use anyhow::{bail, Result};
+use log::{debug, info, warn};
+use osmpbfreader::{
+    fileformat::Blob, osmformat::PrimitiveBlock, Node, NodeId, OsmId, OsmObj, OsmPbfReader,
+    StoreObjs, Way, WayId,
+};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-/// Load PBF file from `path` and filter contents using `pred`.
-pub fn load_relations<F>(path: PathBuf, pred: F) -> Result<BTreeMap<OsmId, OsmObj>>
+use crate::{geom::BBox, xml};
+
+/// Map from ids to the objects loaded from PBF or OSM XML files. Its iteration order is
+/// arbitrary; use `matching` to get objects in a stable order.
+pub type Objects = FxHashMap<OsmId, OsmObj>;
+
+/// Adapter to let `OsmPbfReader` fill `Objects`.
+struct Store(Objects);
+
+impl StoreObjs for Store {
+    fn insert(&mut self, key: OsmId, value: OsmObj) {
+        self.0.insert(key, value);
+    }
+
+    fn contains_key(&self, key: &OsmId) -> bool {
+        self.0.contains_key(key)
+    }
+}
+
+/// Reader failing at the end of the file instead of reading nothing. `OsmPbfReader` panics if a
+/// block is cut off, like in a truncated download, but fails on this error. At the end of the
+/// last complete block it ends normally, as it treats an error reading the size of the next
+/// block like the end.
+struct FailAtEof<R>(R);
+
+impl<R: Read> Read for FailAtEof<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf)? {
+            0 if !buf.is_empty() => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file ends in the middle of a block",
+            )),
+            len => Ok(len),
+        }
+    }
+}
+
+impl<R: Seek> Seek for FailAtEof<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+/// Reader for the PBF `file`.
+fn pbf_reader(file: File) -> OsmPbfReader<FailAtEof<File>> {
+    OsmPbfReader::new(FailAtEof(file))
+}
+
+/// Counter logging its count every `interval` ticks, if enabled.
+pub struct Progress {
+    what: &'static str,
+    interval: usize,
+    enabled: bool,
+    count: usize,
+}
+
+impl Progress {
+    /// Create a counter logging "`what` `count`", e.g. "Scanned objects: 1000".
+    #[must_use]
+    pub fn new(what: &'static str, interval: usize, enabled: bool) -> Self {
+        Self {
+            what,
+            interval,
+            enabled,
+            count: 0,
+        }
+    }
+
+    /// Count one more item.
+    pub fn tick(&mut self) {
+        self.count += 1;
+        if self.enabled && self.count.is_multiple_of(self.interval) {
+            info!("{}: {}", self.what, self.count);
+        }
+    }
+}
+
+/// Wrap `pred` to log how many objects were scanned, if `enabled`.
+pub fn with_progress<F>(mut pred: F, enabled: bool) -> impl FnMut(&OsmObj) -> bool
 where
     F: FnMut(&OsmObj) -> bool,
 {
-    let f = std::fs::File::open(path)?;
-    let mut pbf = OsmPbfReader::new(f);
-    let relations = pbf.get_objs_and_deps(pred)?;
+    let mut progress = Progress::new("Scanned objects", 1_000_000, enabled);
+
+    move |obj: &OsmObj| {
+        progress.tick();
+        pred(obj)
+    }
+}
+
+/// Wrap `pred` like `with_progress`, but for calling from several threads.
+pub fn with_progress_sync<F>(pred: F, enabled: bool) -> impl Fn(&OsmObj) -> bool + Sync
+where
+    F: Fn(&OsmObj) -> bool + Sync,
+{
+    let count = AtomicUsize::new(0);
+
+    move |obj: &OsmObj| {
+        if enabled {
+            let count = count.fetch_add(1, Ordering::Relaxed) + 1;
+            if count.is_multiple_of(1_000_000) {
+                info!("Scanned objects: {count}");
+            }
+        }
+        pred(obj)
+    }
+}
+
+/// Open the PBF or OSM XML file at `path`. Files compressed with gzip or bzip2, as detected by
+/// their first bytes, are decompressed into an anonymous temporary file first, since reading PBF
+/// needs to seek. That file takes as much disk space as the uncompressed input and is removed on
+/// close.
+///
+/// # Errors
+///
+/// Fails if the file cannot be read or decompressed.
+pub fn open(path: &Path) -> Result<File> {
+    let mut file = File::open(path)?;
+
+    let mut magic = [0; 3];
+    let len = file.read(&mut magic)?;
+    file.rewind()?;
+
+    let mut decoder: Box<dyn Read> = match &magic[..len] {
+        [0x1f, 0x8b, ..] => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        b"BZh" => Box::new(bzip2::read::MultiBzDecoder::new(file)),
+        _ => return Ok(file),
+    };
+
+    debug!("Decompressing {path:?} to a temporary file");
+    let mut decompressed = tempfile::tempfile()?;
+    io::copy(&mut decoder, &mut decompressed)?;
+    decompressed.rewind()?;
+
+    Ok(decompressed)
+}
+
+/// Whether `file` holds OSM XML rather than PBF, judged by its first bytes: XML starts with `<`
+/// after an optional byte order mark and whitespace, PBF with the length of its first header.
+fn is_xml(file: &mut File) -> Result<bool> {
+    let mut start = [0; 64];
+    let len = file.read(&mut start)?;
+    file.rewind()?;
+
+    let start = &start[..len];
+    let start = start.strip_prefix(b"\xef\xbb\xbf").unwrap_or(start);
+    Ok(start.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'<'))
+}
+
+/// The ids of the members of a relation or the nodes of a way.
+fn dependencies(obj: &OsmObj) -> Vec<OsmId> {
+    match obj {
+        OsmObj::Relation(relation) => relation.refs.iter().map(|r| r.member).collect(),
+        OsmObj::Way(way) => way.nodes.iter().map(|&n| OsmId::Node(n)).collect(),
+        OsmObj::Node(_) => vec![],
+    }
+}
+
+/// Read the objects in the OSM XML `file` matching `pred` and their dependencies. The whole
+/// file is held in memory, which is fine for the small extracts usually stored as XML.
+fn load_xml(file: File, mut pred: impl FnMut(&OsmObj) -> bool) -> Result<Objects> {
+    let mut all = Objects::default();
+    let mut pending = vec![];
+    xml::read(BufReader::new(file), |obj| {
+        if pred(&obj) {
+            pending.push(obj.id());
+        }
+        all.insert(obj.id(), obj);
+        Ok(())
+    })?;
+
+    let mut objs = Objects::default();
+    while let Some(id) = pending.pop() {
+        // Objects already moved over, or missing from the file, are skipped.
+        let Some(obj) = all.remove(&id) else {
+            continue;
+        };
+        pending.extend(dependencies(&obj));
+        objs.insert(id, obj);
+    }
+
+    Ok(objs)
+}
+
+/// Call `f` with each object in the PBF or OSM XML file at `path`, without loading dependencies.
+///
+/// # Errors
+///
+/// Fails if the file cannot be opened or decoded, or if `f` fails.
+pub fn for_each_object(path: &Path, mut f: impl FnMut(OsmObj) -> Result<()>) -> Result<()> {
+    let mut file = open(path)?;
+    if is_xml(&mut file)? {
+        return xml::read(BufReader::new(file), f);
+    }
+
+    let mut pbf = pbf_reader(file);
+    for obj in pbf.par_iter() {
+        f(obj?)?;
+    }
+
+    Ok(())
+}
+
+/// Load PBF or OSM XML files from `paths` and filter contents using `pred`. Objects from all files
+/// are merged; if an object is contained in several files, the one read last wins.
+///
+/// With `skip_errors` PBF blocks which cannot be decoded are logged and skipped, and a file is
+/// only read up to a block which cannot be read at all, like the end of a truncated download.
+/// Objects and dependencies in these blocks are missing from the result.
+///
+/// # Errors
+///
+/// Fails if a file cannot be opened or, without `skip_errors`, decoded.
+pub fn load_relations<F>(paths: &[PathBuf], mut pred: F, skip_errors: bool) -> Result<Objects>
+where
+    F: FnMut(&OsmObj) -> bool,
+{
+    let mut relations = Objects::default();
+
+    for path in paths {
+        let mut file = open(path)?;
+        let objs = if is_xml(&mut file)? {
+            load_xml(file, &mut pred)?
+        } else if skip_errors {
+            let mut pbf = pbf_reader(file);
+            let mut found = vec![];
+            for (i, blob) in pbf.blobs().enumerate() {
+                if let Some(block) = decode(i, blob, true) {
+                    found.extend(osmpbfreader::blocks::iter(&block?).filter(|obj| pred(obj)));
+                }
+            }
+            with_dependencies(&mut pbf, found, true)?
+        } else {
+            let mut store = Store(Objects::default());
+            pbf_reader(file).get_objs_and_deps_store(&mut pred, &mut store)?;
+            store.0
+        };
+
+        for (id, obj) in objs {
+            if relations.insert(id, obj).is_some() {
+                debug!("{id:?} from {path:?} replaces an object read before");
+            }
+        }
+    }
+
+    Ok(relations)
+}
+
+/// Load PBF files like `load_relations`, but decode blocks and evaluate `pred` on the rayon
+/// thread pool. Objects found in a pass are merged in the order of their blocks, so the result
+/// is the same as with `load_relations`. OSM XML files and `skip_errors` are handled as there.
+///
+/// # Errors
+///
+/// Fails if a file cannot be opened or, without `skip_errors`, decoded.
+pub fn load_relations_par<F>(paths: &[PathBuf], pred: F, skip_errors: bool) -> Result<Objects>
+where
+    F: Fn(&OsmObj) -> bool + Sync,
+{
+    let mut relations = Objects::default();
+
+    for path in paths {
+        let mut file = open(path)?;
+        if is_xml(&mut file)? {
+            for (id, obj) in load_xml(file, &pred)? {
+                if relations.insert(id, obj).is_some() {
+                    debug!("{id:?} from {path:?} replaces an object read before");
+                }
+            }
+            continue;
+        }
+        let mut pbf = pbf_reader(file);
+        let found = scan(&mut pbf, &pred, skip_errors)?;
+
+        for (id, obj) in with_dependencies(&mut pbf, found, skip_errors)? {
+            if relations.insert(id, obj).is_some() {
+                debug!("{id:?} from {path:?} replaces an object read before");
+            }
+        }
+    }
+
     Ok(relations)
 }
+
+/// Prefilter for objects in the files at `paths` which may intersect `bbox`, to apply before
+/// their dependencies are loaded. A first pass over the files records the positions of all
+/// nodes to compute the envelope of each way; afterwards only these envelopes are kept. Ways and
+/// relations whose member ways all have envelopes, which together don't overlap `bbox`, are
+/// rejected. Anything else, like relations with member relations, is left for the exact check
+/// on the assembled geometry.
+///
+/// # Errors
+///
+/// Fails if a file cannot be opened or decoded.
+pub fn by_bbox(paths: &[PathBuf], bbox: BBox) -> Result<impl Fn(&OsmObj) -> bool + Sync> {
+    let mut positions = FxHashMap::<NodeId, (f64, f64)>::default();
+    let mut ways = FxHashMap::<WayId, BBox>::default();
+
+    for path in paths {
+        for_each_object(path, |obj| {
+            match obj {
+                OsmObj::Node(node) => {
+                    positions.insert(node.id, (node.lon(), node.lat()));
+                }
+                OsmObj::Way(way) => {
+                    let envelope = way.nodes.iter().try_fold(None, |envelope, id| {
+                        let &(lon, lat) = positions.get(id)?;
+                        let point = BBox {
+                            min_lon: lon,
+                            min_lat: lat,
+                            max_lon: lon,
+                            max_lat: lat,
+                        };
+                        Some(Some(envelope.map_or(point, |e: BBox| e.union(&point))))
+                    });
+                    // Ways with nodes missing from the files are left undecided.
+                    if let Some(Some(envelope)) = envelope {
+                        ways.insert(way.id, envelope);
+                    }
+                }
+                OsmObj::Relation(_) => {}
+            }
+            Ok(())
+        })?;
+    }
+    drop(positions);
+
+    Ok(move |obj: &OsmObj| {
+        let envelope = match obj {
+            OsmObj::Node(_) => return true,
+            OsmObj::Way(way) => ways.get(&way.id).copied(),
+            OsmObj::Relation(relation) => {
+                let mut envelope: Option<BBox> = None;
+                for member in &relation.refs {
+                    match member.member {
+                        OsmId::Way(id) => {
+                            let Some(way) = ways.get(&id) else {
+                                return true;
+                            };
+                            envelope = Some(envelope.map_or(*way, |e| e.union(way)));
+                        }
+                        OsmId::Relation(_) => return true,
+                        OsmId::Node(_) => {}
+                    }
+                }
+                envelope
+            }
+        };
+        envelope.is_none_or(|envelope| envelope.overlaps(&bbox))
+    })
+}
+
+/// The `found` objects of `pbf` with their members and nodes, read in further passes until none
+/// are missing. `skip_errors` is handled like in `load_relations`.
+fn with_dependencies<R>(
+    pbf: &mut OsmPbfReader<R>,
+    mut found: Vec<OsmObj>,
+    skip_errors: bool,
+) -> Result<Objects>
+where
+    R: Read + Seek + Send,
+{
+    let mut objs = Objects::default();
+    while !found.is_empty() {
+        let mut deps = FxHashSet::default();
+        for obj in &found {
+            deps.extend(dependencies(obj));
+        }
+        objs.extend(found.into_iter().map(|obj| (obj.id(), obj)));
+        deps.retain(|id| !objs.contains_key(id));
+
+        found = if deps.is_empty() {
+            vec![]
+        } else {
+            scan(pbf, |obj: &OsmObj| deps.contains(&obj.id()), skip_errors)?
+        };
+    }
+
+    Ok(objs)
+}
+
+/// Decode the `i`th `blob` of a PBF file. With `skip_errors` a blob which cannot be read or
+/// decoded is logged and skipped by returning `None`.
+fn decode(
+    i: usize,
+    blob: osmpbfreader::Result<Blob>,
+    skip_errors: bool,
+) -> Option<Result<PrimitiveBlock>> {
+    match blob.and_then(|blob| osmpbfreader::primitive_block_from_blob(&blob)) {
+        Ok(block) => Some(Ok(block)),
+        Err(e) if skip_errors => {
+            warn!("skipping block {i} which cannot be read: {e}");
+            None
+        }
+        Err(e) => Some(Err(e.into())),
+    }
+}
+
+/// Decode all blocks of `pbf` in parallel and collect the objects matching `pred`, in file order.
+/// `skip_errors` is handled like in `load_relations`.
+fn scan<R>(
+    pbf: &mut OsmPbfReader<R>,
+    pred: impl Fn(&OsmObj) -> bool + Sync,
+    skip_errors: bool,
+) -> Result<Vec<OsmObj>>
+where
+    R: Read + Seek + Send,
+{
+    pbf.rewind()?;
+
+    let mut blocks = pbf
+        .blobs()
+        .enumerate()
+        .par_bridge()
+        .filter_map(|(i, blob)| {
+            let block = decode(i, blob, skip_errors)?;
+            Some(block.map(|block| {
+                let objs = osmpbfreader::blocks::iter(&block)
+                    .filter(|obj| pred(obj))
+                    .collect::<Vec<_>>();
+                (i, objs)
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    blocks.sort_unstable_by_key(|(i, _)| *i);
+
+    Ok(blocks.into_iter().flat_map(|(_, objs)| objs).collect())
+}
+
+/// The objects in `objs` matching `pred`, ordered by id.
+pub fn matching(objs: &Objects, pred: impl Fn(&OsmObj) -> bool) -> Vec<&OsmObj> {
+    let mut matching = objs.values().filter(|obj| pred(obj)).collect::<Vec<_>>();
+    matching.sort_unstable_by_key(|obj| obj.id());
+    matching
+}
+
+/// Stream the relations in the PBF file at `path` matching `pred`. For each relation `f` is called
+/// with a map holding the relation and its member ways and their nodes.
+///
+/// In contrast to `load_relations` the file is read in three passes (relations, ways, nodes) and
+/// nodes are only kept while a relation which was not yet passed to `f` needs them. Relations are
+/// passed in the order in which their nodes were read; relations with members missing from the
+/// file are passed last with whatever members were found.
+///
+/// # Errors
+///
+/// Fails if the file cannot be opened or decoded, or if `f` fails.
+pub fn for_each_relation<F, G>(path: &Path, mut pred: F, mut f: G) -> Result<()>
+where
+    F: FnMut(&OsmObj) -> bool,
+    G: FnMut(&Objects) -> Result<()>,
+{
+    let mut file = open(path)?;
+    if is_xml(&mut file)? {
+        bail!("streaming is only implemented for PBF input");
+    }
+    let mut pbf = pbf_reader(file);
+
+    let mut relations = Vec::new();
+    let mut way_ids = HashSet::new();
+    for obj in pbf.par_iter() {
+        let obj = obj?;
+        if let Some(relation) = obj.relation().filter(|_| pred(&obj)) {
+            way_ids.extend(relation.refs.iter().filter_map(|r| r.member.way()));
+            relations.push(obj);
+        }
+    }
+
+    pbf.rewind()?;
+    let mut ways = HashMap::new();
+    for obj in pbf.par_iter() {
+        if let OsmObj::Way(way) = obj? {
+            if way_ids.contains(&way.id) {
+                ways.insert(way.id, way);
+            }
+        }
+    }
+
+    // The nodes each relation still waits for, and the relations waiting for each node.
+    let mut pending = relations
+        .iter()
+        .map(|relation| {
+            member_ways(relation, &ways)
+                .flat_map(|way| way.nodes.iter().copied())
+                .collect::<HashSet<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut waiting = HashMap::<NodeId, Vec<usize>>::new();
+    for (i, node_ids) in pending.iter().enumerate() {
+        for node_id in node_ids {
+            waiting.entry(*node_id).or_default().push(i);
+        }
+    }
+
+    let mut done = vec![false; relations.len()];
+    let mut nodes = HashMap::new();
+
+    for (i, relation) in relations.iter().enumerate() {
+        if pending[i].is_empty() {
+            f(&with_members(relation, &ways, &nodes))?;
+            done[i] = true;
+        }
+    }
+
+    pbf.rewind()?;
+    for obj in pbf.par_iter() {
+        let OsmObj::Node(node) = obj? else {
+            continue;
+        };
+        let Some(waiting_relations) = waiting.get(&node.id).cloned() else {
+            continue;
+        };
+
+        let node_id = node.id;
+        nodes.insert(node_id, node);
+
+        for i in waiting_relations {
+            pending[i].remove(&node_id);
+            if !pending[i].is_empty() {
+                continue;
+            }
+
+            f(&with_members(&relations[i], &ways, &nodes))?;
+            done[i] = true;
+
+            // Release nodes no other pending relation needs.
+            for node_id in member_ways(&relations[i], &ways).flat_map(|way| way.nodes.iter()) {
+                if let Some(relations) = waiting.get_mut(node_id) {
+                    relations.retain(|&j| j != i);
+                    if relations.is_empty() {
+                        waiting.remove(node_id);
+                        nodes.remove(node_id);
+                    }
+                }
+            }
+        }
+    }
+
+    for (relation, _) in relations.iter().zip(done).filter(|(_, done)| !done) {
+        f(&with_members(relation, &ways, &nodes))?;
+    }
+
+    Ok(())
+}
+
+fn member_ways<'a>(
+    relation: &'a OsmObj,
+    ways: &'a HashMap<WayId, Way>,
+) -> impl Iterator<Item = &'a Way> {
+    relation
+        .relation()
+        .into_iter()
+        .flat_map(|relation| relation.refs.iter())
+        .filter_map(|r| ways.get(&r.member.way()?))
+}
+
+/// Collect `relation` with its member ways and nodes as far as they are available.
+fn with_members(
+    relation: &OsmObj,
+    ways: &HashMap<WayId, Way>,
+    nodes: &HashMap<NodeId, Node>,
+) -> Objects {
+    let mut objs = Objects::default();
+
+    for way in member_ways(relation, ways) {
+        for node in way.nodes.iter().filter_map(|node_id| nodes.get(node_id)) {
+            objs.insert(OsmId::Node(node.id), OsmObj::Node(node.clone()));
+        }
+        objs.insert(OsmId::Way(way.id), OsmObj::Way(way.clone()));
+    }
+    objs.insert(relation.id(), relation.clone());
+
+    objs
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Read};
+
+    use super::FailAtEof;
+
+    #[test]
+    fn fail_at_eof() {
+        let mut reader = FailAtEof(&[1, 2, 3][..]);
+        let mut buf = [0; 2];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(reader.read(&mut []).unwrap(), 0);
+        assert_eq!(
+            reader.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+}
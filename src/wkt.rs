@@ -0,0 +1,100 @@
+use std::io::{self, Write};
+
+use anyhow::Result;
+use geojson::{PolygonType, Position, Value};
+use itertools::Itertools;
+use osmpbfreader::OsmObj;
+
+use crate::{geom, util::Objects};
+
+/// Write the WKT geometry for each object in `objs` matching `pred`, one per line.
+///
+/// # Errors
+///
+/// Fails if writing to `out` fails or, with `options.strict`, if an object cannot be converted.
+pub fn write(
+    objs: &Objects,
+    pred: impl Fn(&OsmObj) -> bool,
+    out: impl io::Write,
+    options: &geom::Options,
+) -> Result<geom::Summary> {
+    // Use a buffered writer to amortize flushes.
+    let mut buffer = options.buffer(out);
+    let mut summary = geom::Summary::default();
+
+    for feature in geom::features(objs, pred, options, &mut summary.skipped) {
+        if let Some(geometry) = feature?.geometry {
+            writeln!(buffer, "{}", to_wkt(&geometry.value))?;
+            summary.written += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Serialize a `geojson` geometry as WKT.
+#[must_use]
+pub fn to_wkt(geometry: &Value) -> String {
+    match geometry {
+        Value::Point(p) => format!("POINT({})", position(p)),
+        Value::MultiPoint(ps) => format!("MULTIPOINT({})", positions(ps)),
+        Value::LineString(ps) => format!("LINESTRING({})", positions(ps)),
+        Value::MultiLineString(lines) => {
+            format!(
+                "MULTILINESTRING({})",
+                lines
+                    .iter()
+                    .map(|ps| format!("({})", positions(ps)))
+                    .join(",")
+            )
+        }
+        Value::Polygon(rings) => format!("POLYGON({})", polygon(rings)),
+        Value::MultiPolygon(polygons) => format!(
+            "MULTIPOLYGON({})",
+            polygons
+                .iter()
+                .map(|rings| format!("({})", polygon(rings)))
+                .join(",")
+        ),
+        Value::GeometryCollection(geometries) => format!(
+            "GEOMETRYCOLLECTION({})",
+            geometries.iter().map(|g| to_wkt(&g.value)).join(",")
+        ),
+    }
+}
+
+fn position(p: &Position) -> String {
+    p.iter().join(" ")
+}
+
+fn positions(ps: &[Position]) -> String {
+    ps.iter().map(position).join(",")
+}
+
+fn polygon(rings: &PolygonType) -> String {
+    rings
+        .iter()
+        .map(|ring| format!("({})", positions(ring)))
+        .join(",")
+}
+
+#[cfg(test)]
+mod test {
+    use geojson::Value;
+
+    use super::to_wkt;
+
+    #[test]
+    fn polygon() {
+        let ring = vec![vec![0., 0.], vec![1., 0.], vec![1., 1.5], vec![0., 0.]];
+
+        assert_eq!(
+            to_wkt(&Value::Polygon(vec![ring.clone()])),
+            "POLYGON((0 0,1 0,1 1.5,0 0))"
+        );
+        assert_eq!(
+            to_wkt(&Value::MultiPolygon(vec![vec![ring.clone(), ring]])),
+            "MULTIPOLYGON(((0 0,1 0,1 1.5,0 0),(0 0,1 0,1 1.5,0 0)))"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::BufRead;
+
+use anyhow::{anyhow, bail, Context, Result};
+use osmpbfreader::{Node, NodeId, OsmId, OsmObj, Ref, Relation, RelationId, Tags, Way, WayId};
+use quick_xml::events::{BytesStart, Event};
+
+/// Read the nodes, ways and relations of an OSM XML document from `input` and call `f` with each
+/// of them, in document order. Other elements are ignored.
+///
+/// # Errors
+///
+/// Fails if the document is malformed, an element lacks a required attribute or `f` fails.
+pub fn read(input: impl BufRead, mut f: impl FnMut(OsmObj) -> Result<()>) -> Result<()> {
+    let mut reader = quick_xml::Reader::from_reader(input);
+    let mut buf = Vec::new();
+    let mut current: Option<OsmObj> = None;
+
+    loop {
+        let position = reader.buffer_position();
+        let event = reader
+            .read_event_into(&mut buf)
+            .with_context(|| format!("invalid XML at byte {position}"))?;
+
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                let element = e.name();
+                match (element.as_ref(), current.as_mut()) {
+                    (b"node" | b"way" | b"relation", _) => {
+                        let obj = object(e)?;
+                        if matches!(event, Event::Empty(_)) {
+                            f(obj)?;
+                        } else {
+                            current = Some(obj);
+                        }
+                    }
+                    (b"tag", Some(obj)) => {
+                        let tags = match obj {
+                            OsmObj::Node(node) => &mut node.tags,
+                            OsmObj::Way(way) => &mut way.tags,
+                            OsmObj::Relation(relation) => &mut relation.tags,
+                        };
+                        tags.insert(attribute(e, "k")?.into(), attribute(e, "v")?.into());
+                    }
+                    (b"nd", Some(OsmObj::Way(way))) => {
+                        way.nodes.push(NodeId(attribute(e, "ref")?.parse()?));
+                    }
+                    (b"member", Some(OsmObj::Relation(relation))) => {
+                        let id = attribute(e, "ref")?.parse()?;
+                        let member = match attribute(e, "type")?.as_str() {
+                            "node" => OsmId::Node(NodeId(id)),
+                            "way" => OsmId::Way(WayId(id)),
+                            "relation" => OsmId::Relation(RelationId(id)),
+                            other => bail!("unknown member type '{other}'"),
+                        };
+                        relation.refs.push(Ref {
+                            member,
+                            role: attribute(e, "role").unwrap_or_default().into(),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(e) if matches!(e.name().as_ref(), b"node" | b"way" | b"relation") => {
+                if let Some(obj) = current.take() {
+                    f(obj)?;
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(())
+}
+
+/// The object started by `element`, without tags, nodes or members yet.
+fn object(element: &BytesStart) -> Result<OsmObj> {
+    let id = attribute(element, "id")?.parse()?;
+
+    Ok(match element.name().as_ref() {
+        b"node" => OsmObj::Node(Node {
+            id: NodeId(id),
+            tags: Tags::new(),
+            decimicro_lat: decimicro(&attribute(element, "lat")?)?,
+            decimicro_lon: decimicro(&attribute(element, "lon")?)?,
+        }),
+        b"way" => OsmObj::Way(Way {
+            id: WayId(id),
+            tags: Tags::new(),
+            nodes: vec![],
+        }),
+        _ => OsmObj::Relation(Relation {
+            id: RelationId(id),
+            tags: Tags::new(),
+            refs: vec![],
+        }),
+    })
+}
+
+/// The unescaped value of the attribute `key` of `element`.
+fn attribute(element: &BytesStart, key: &str) -> Result<String> {
+    let attribute = element
+        .try_get_attribute(key)?
+        .ok_or_else(|| anyhow!("attribute '{key}' is missing"))?;
+    Ok(attribute.unescape_value()?.into_owned())
+}
+
+/// Convert a coordinate in degrees like "50.1234567" to units of 100 nanodegrees, as stored in
+/// PBF. Digits beyond the seventh decimal place are cut off.
+fn decimicro(value: &str) -> Result<i32> {
+    let (integer, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let fraction = &fraction[..fraction.len().min(7)];
+    let decimicro = format!("{integer}{fraction:0<7}")
+        .parse::<i64>()
+        .with_context(|| format!("invalid coordinate '{value}'"))?;
+
+    i32::try_from(decimicro).with_context(|| format!("coordinate '{value}' is out of range"))
+}
+
+#[cfg(test)]
+mod test {
+    use osmpbfreader::{NodeId, OsmId, OsmObj, WayId};
+
+    use super::{decimicro, read};
+
+    #[test]
+    fn read_objects() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <osm version="0.6">
+              <bounds minlat="50" minlon="7" maxlat="51" maxlon="8"/>
+              <node id="1" lat="50.5" lon="-7.25"/>
+              <node id="2" lat="51" lon="8"><tag k="name" v="A &amp; B"/></node>
+              <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="road"/></way>
+              <relation id="100">
+                <member type="way" ref="10" role="outer"/>
+                <member type="node" ref="2" role=""/>
+                <tag k="type" v="boundary"/>
+              </relation>
+            </osm>"#;
+
+        let mut objs = vec![];
+        read(xml.as_bytes(), |obj| {
+            objs.push(obj);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(objs.len(), 4);
+        let OsmObj::Node(node) = &objs[0] else {
+            panic!("expected a node");
+        };
+        assert_eq!(
+            (node.decimicro_lat, node.decimicro_lon),
+            (505_000_000, -72_500_000)
+        );
+        assert_eq!(
+            objs[1].tags().get("name").map(|n| n.as_str()),
+            Some("A & B")
+        );
+        assert_eq!(objs[2].way().unwrap().nodes, [NodeId(1), NodeId(2)]);
+
+        let relation = objs[3].relation().unwrap();
+        assert_eq!(relation.refs[0].member, OsmId::Way(WayId(10)));
+        assert_eq!(relation.refs[0].role, "outer");
+        assert_eq!(relation.refs[1].member, OsmId::Node(NodeId(2)));
+        assert!(objs[3].tags().contains("type", "boundary"));
+    }
+
+    #[test]
+    fn parse_decimicro() {
+        assert_eq!(decimicro("50").unwrap(), 500_000_000);
+        assert_eq!(decimicro("-0.5").unwrap(), -5_000_000);
+        assert_eq!(decimicro("13.12345678").unwrap(), 131_234_567);
+        assert!(decimicro("x").is_err());
+        assert!(decimicro("300").is_err());
+    }
+}